@@ -0,0 +1,236 @@
+-- Lookups depend on the loaded cache and the kq.currency.* settings.
+ALTER FUNCTION "kq_fx_get_rate"(bigint, bigint, date) STABLE;
+ALTER FUNCTION "kq_fx_get_rate_xuid"(text, text, date) STABLE;
+
+-- The cache holds rate types and scenarios.
+DROP FUNCTION "kq_fx_display_cache"();
+CREATE FUNCTION "kq_fx_display_cache"() RETURNS TABLE (
+	"currency_id" bigint,
+	"to_currency_id" bigint,
+	"date" date,
+	"rate" double precision,
+	"rate_type" TEXT,
+	"scenario" TEXT
+)
+PARALLEL SAFE STRICT
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'kq_fx_display_cache_wrapper';
+
+-- Cache management.
+CREATE FUNCTION "kq_fx_refresh_cache"() RETURNS TEXT
+STRICT
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'kq_fx_refresh_cache_wrapper';
+
+CREATE FUNCTION "kq_fx_refresh_pair"(
+	"currency_id" bigint,
+	"to_currency_id" bigint
+) RETURNS TEXT
+STRICT
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'kq_fx_refresh_pair_wrapper';
+
+-- Automatic invalidation.
+CREATE FUNCTION "kq_fx_invalidate_cache_trigger"() RETURNS TRIGGER
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'kq_fx_invalidate_cache_trigger_wrapper';
+
+CREATE FUNCTION "kq_fx_refresh_pair_trigger"() RETURNS TRIGGER
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'kq_fx_refresh_pair_trigger_wrapper';
+
+CREATE FUNCTION "kq_fx_install_triggers"(
+	"tables" TEXT[] DEFAULT ARRAY['plan.currency', 'plan.fx_rate']
+) RETURNS TEXT
+STRICT
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'kq_fx_install_triggers_wrapper';
+
+CREATE FUNCTION "kq_fx_uninstall_triggers"(
+	"tables" TEXT[] DEFAULT ARRAY['plan.currency', 'plan.fx_rate']
+) RETURNS TEXT
+STRICT
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'kq_fx_uninstall_triggers_wrapper';
+
+CREATE FUNCTION "kq_fx_install_pair_triggers"(
+	"table" TEXT DEFAULT 'plan.fx_rate',
+	"currency_column" TEXT DEFAULT 'currency_id',
+	"to_currency_column" TEXT DEFAULT 'to_currency_id'
+) RETURNS TEXT
+STRICT
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'kq_fx_install_pair_triggers_wrapper';
+
+-- Lookups.
+CREATE FUNCTION "kq_fx_get_rate_at"(
+	"currency_id" bigint,
+	"to_currency_id" bigint,
+	"timestamp" timestamp with time zone
+) RETURNS double precision
+STABLE PARALLEL SAFE STRICT
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'kq_fx_get_rate_at_wrapper';
+
+CREATE FUNCTION "kq_fx_get_rate_at_local"(
+	"currency_id" bigint,
+	"to_currency_id" bigint,
+	"timestamp" timestamp without time zone
+) RETURNS double precision
+STABLE PARALLEL SAFE STRICT
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'kq_fx_get_rate_at_local_wrapper';
+
+CREATE FUNCTION "kq_fx_get_rate"(
+	"currency_id" bigint,
+	"to_currency_id" bigint,
+	"date" date,
+	"rate_type" TEXT
+) RETURNS double precision
+STABLE PARALLEL SAFE STRICT
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'kq_fx_get_rate_by_type_wrapper';
+
+CREATE FUNCTION "kq_fx_get_rate_scenario"(
+	"currency_id" bigint,
+	"to_currency_id" bigint,
+	"date" date,
+	"scenario" TEXT,
+	"rate_type" TEXT DEFAULT NULL
+) RETURNS double precision
+STABLE PARALLEL SAFE
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'kq_fx_get_rate_scenario_wrapper';
+
+CREATE FUNCTION "kq_fx_get_rate_numeric"(
+	"currency_id" bigint,
+	"to_currency_id" bigint,
+	"date" date
+) RETURNS NUMERIC
+STABLE PARALLEL SAFE STRICT
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'kq_fx_get_rate_numeric_wrapper';
+
+CREATE FUNCTION "kq_fx_get_rate_interpolated"(
+	"currency_id" bigint,
+	"to_currency_id" bigint,
+	"date" date
+) RETURNS double precision
+STABLE PARALLEL SAFE STRICT
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'kq_fx_get_rate_interpolated_wrapper';
+
+CREATE FUNCTION "kq_fx_get_rate_source"(
+	"currency_id" bigint,
+	"to_currency_id" bigint,
+	"date" date
+) RETURNS TEXT
+STABLE PARALLEL SAFE STRICT
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'kq_fx_get_rate_source_wrapper';
+
+CREATE TYPE kq_fx_quote AS (
+    bid double precision,
+    mid double precision,
+    ask double precision
+);
+
+CREATE FUNCTION "kq_fx_get_quote"(
+	"currency_id" bigint,
+	"to_currency_id" bigint,
+	"date" date
+) RETURNS kq_fx_quote
+STABLE PARALLEL SAFE STRICT
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'kq_fx_get_quote_wrapper';
+
+CREATE FUNCTION "kq_fx_get_rate_history"(
+	"currency_id" bigint,
+	"to_currency_id" bigint,
+	"start_date" date,
+	"end_date" date,
+	"daily" bool DEFAULT false
+) RETURNS TABLE (
+	"date" date,
+	"rate" double precision
+)
+STABLE PARALLEL SAFE STRICT
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'kq_fx_get_rate_history_wrapper';
+
+CREATE FUNCTION "kq_fx_rate_series"(
+	"currency_id" bigint,
+	"to_currency_id" bigint,
+	"start_date" date,
+	"end_date" date,
+	"step" interval DEFAULT '1 day'
+) RETURNS TABLE (
+	"date" date,
+	"rate" double precision
+)
+STABLE PARALLEL SAFE STRICT
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'kq_fx_rate_series_wrapper';
+
+CREATE FUNCTION "kq_fx_get_rate_path"(
+	"currency_id" bigint,
+	"to_currency_id" bigint,
+	"date" date
+) RETURNS TABLE (
+	"rate" double precision,
+	"path" bigint[]
+)
+STABLE PARALLEL SAFE STRICT
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'kq_fx_get_rate_path_wrapper';
+
+-- Amount conversion.
+CREATE FUNCTION "kq_fx_convert"(
+	"amount" double precision,
+	"currency_id" bigint,
+	"to_currency_id" bigint,
+	"date" date,
+	"round" bool DEFAULT false,
+	"side" TEXT DEFAULT 'mid'
+) RETURNS double precision
+STABLE PARALLEL SAFE STRICT
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'kq_fx_convert_wrapper';
+
+CREATE FUNCTION "kq_fx_convert"(
+	"amount" NUMERIC,
+	"currency_id" bigint,
+	"to_currency_id" bigint,
+	"date" date,
+	"round" bool DEFAULT false,
+	"side" TEXT DEFAULT 'mid'
+) RETURNS NUMERIC
+STABLE PARALLEL SAFE STRICT
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'kq_fx_convert_numeric_wrapper';
+
+CREATE FUNCTION "kq_fx_convert_xuid"(
+	"amount" double precision,
+	"currency_xuid" TEXT,
+	"to_currency_xuid" TEXT,
+	"date" date,
+	"round" bool DEFAULT false,
+	"side" TEXT DEFAULT 'mid'
+) RETURNS double precision
+STABLE PARALLEL SAFE STRICT
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'kq_fx_convert_xuid_wrapper';
+
+CREATE FUNCTION "kq_fx_convert_xuid"(
+	"amount" NUMERIC,
+	"currency_xuid" TEXT,
+	"to_currency_xuid" TEXT,
+	"date" date,
+	"round" bool DEFAULT false,
+	"side" TEXT DEFAULT 'mid'
+) RETURNS NUMERIC
+STABLE PARALLEL SAFE STRICT
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'kq_fx_convert_xuid_numeric_wrapper';
+
+DO $$ begin RAISE NOTICE 'ketteQ FX Upgrade: kq_fx: 1.0.1 -> 1.0.2 completed.'; end; $$;
@@ -45,7 +45,8 @@ INSERT INTO plan.currency (id, xuid,"name",description,"attributes") VALUES
                                                                      (2, 'cad','CAD',NULL,NULL),
                                                                      (3, 'eur','EUR',NULL,NULL),
                                                                      (3590000231158, 'nzd','NZD',NULL,NULL),
-                                                                     (3590000203070, 'aud','AUD',NULL,NULL);
+                                                                     (3590000203070, 'aud','AUD',NULL,NULL),
+                                                                     (4, 'jpy','JPY',NULL,'{"minor_units": 0}');
 
 INSERT INTO plan.fx_rate (currency_id,to_currency_id,"date",rate) VALUES
                                                                       (2,1,'2023-11-01',1.3709),
@@ -54,6 +55,8 @@ INSERT INTO plan.fx_rate (currency_id,to_currency_id,"date",rate) VALUES
                                                                       (2,1,'2024-02-01',1.3501),
                                                                       (2,1,'2024-03-01',1.3539),
                                                                       (3,1,'2024-01-01',0.5366),
+                                                                      (3590000231158,3,'2024-01-01',0),
+                                                                      (1,4,'2024-01-01',148.27),
                                                                       (2,1,'2020-01-01',1.3661417454478324),
                                                                       (2,1,'2020-02-01',1.4646609572087765),
                                                                       (2,1,'2020-03-01',1.0546831390780236),
@@ -769,4 +772,38 @@ INSERT INTO plan.fx_rate (currency_id,to_currency_id,"date",rate) VALUES
                                                                       (3590000203070,3590000231158,'2030-11-01',1.2516953854271264),
                                                                       (3590000203070,3590000231158,'2030-12-01',1.032123786014365);
 
+
+-- RATE SET
+
+CREATE SCHEMA plan_eu;
+
+CREATE TABLE plan_eu.currency (LIKE plan.currency INCLUDING ALL);
+CREATE TABLE plan_eu.fx_rate (LIKE plan.fx_rate INCLUDING ALL);
+
+INSERT INTO plan_eu.currency SELECT * FROM plan.currency;
+INSERT INTO plan_eu.fx_rate (currency_id,to_currency_id,"date",rate) VALUES
+                                                                         (2,3,'2024-01-01',0.6843),
+                                                                         (1,3,'2024-01-01',0.9152);
+
+-- TEST RATE SETS, copies of plan for the tests changing its cache queries or data
+
+DO $$
+DECLARE
+    rate_set text;
+BEGIN
+    FOREACH rate_set IN ARRAY ARRAY['plan_minor_units', 'plan_overflow_truncate', 'plan_overflow_error',
+                                   'plan_overflow_entries', 'plan_refresh', 'plan_triggers',
+                                   'plan_refresh_pair', 'plan_pair_triggers', 'plan_rate_types', 'plan_scenarios',
+                                   'plan_quotes', 'plan_effective_to', 'plan_intraday', 'plan_cache_slot',
+                                   'plan_rate_set', 'plan_exact_rates', 'plan_failed_load'] LOOP
+        EXECUTE format('CREATE SCHEMA %I', rate_set);
+        EXECUTE format('CREATE TABLE %I.currency (LIKE plan.currency INCLUDING ALL)', rate_set);
+        EXECUTE format('CREATE TABLE %I.fx_rate (LIKE plan.fx_rate INCLUDING ALL)', rate_set);
+        EXECUTE format('INSERT INTO %I.currency SELECT * FROM plan.currency', rate_set);
+        EXECUTE format('INSERT INTO %I.fx_rate (currency_id, to_currency_id, "date", rate) '
+                       'SELECT currency_id, to_currency_id, "date", rate FROM plan.fx_rate', rate_set);
+    END LOOP;
+END
+$$;
+
 SELECT pg_sleep(1);
\ No newline at end of file
@@ -1,21 +1,73 @@
-use heapless::Entry;
+mod shared_map;
+
+use pgrx::bgworkers::{BackgroundWorker, BackgroundWorkerBuilder, SignalWakeFlags};
 use pgrx::lwlock::PgLwLock;
 use pgrx::prelude::*;
 use pgrx::shmem::*;
-use pgrx::spi::SpiResult;
-use pgrx::{error, pg_shmem_init, GucContext, GucFlags, GucRegistry, GucSetting};
+use pgrx::spi::{SpiHeapTupleData, SpiResult};
+use pgrx::{
+    composite_type, direct_function_call, error, pg_shmem_init, register_xact_callback, GucContext,
+    GucFlags, GucRegistry, GucSetting, PgXactCallbackEvent,
+};
+use shared_map::{
+    FrozenLayout, FrozenMap, FrozenMapRef, FrozenVecMap, FrozenVecMapRef, SharedAllocation,
+    SharedArea, SharedConditionVariable,
+};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::ffi::CStr;
+use std::ops::{Index, IndexMut};
 use std::str::FromStr;
-use std::time::Duration;
-
-// Capacity params
-// IMPORTANT: All capacity values MUST be a power of 2. E.g. 2^8 = 256, 2^9 = 512, 2^10 = 1024
+use std::time::{Duration, Instant};
 
-const MAX_ENTRIES: usize = 512;
-const MAX_CURRENCIES: usize = 64;
-const MAX_ID_PAIRS: usize = 1024;
 const CURRENCY_XUID_MAX_LEN: usize = 16;
 
+// Decimal places used to round converted amounts when a currency has no minor units
+const DEFAULT_MINOR_UNITS: i32 = 2;
+
+// How often backends waiting for a cache load check the loading backend is alive
+const LOADER_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Upper bound of `kq.currency.max_caches`, the slots reserved in shared memory
+const CACHE_SLOTS: usize = 256;
+
+// Longest rate type name, see `kq.currency.rate_type`
+const RATE_TYPE_MAX_LEN: usize = 32;
+
+// Rate type of the rows without one
+const DEFAULT_RATE_TYPE: RateTypeId = 0;
+
+// Longest scenario name, see `kq.currency.scenario`
+const SCENARIO_MAX_LEN: usize = 32;
+
+// Scenario of the rows without one, the rates the scenarios fall back to
+const BASE_SCENARIO: ScenarioId = 0;
+
+// Longest rate set name, the length of a PostgreSQL identifier
+const RATE_SET_MAX_LEN: usize = 63;
+
+// Pairs marked stale by the row triggers before the whole cache is marked stale instead
+const MAX_STALE_PAIRS: usize = 64;
+
+// How long the preload worker waits before starting again after a failed load
+const PRELOAD_RESTART_INTERVAL: Duration = Duration::from_secs(60);
+
+// Microseconds in a day, see `StoreInstant`
+const USECS_PER_DAY: StoreTime = 86_400_000_000;
+
+// Time of the date lookups, the rates of the whole day are in effect
+const END_OF_DAY: StoreTime = USECS_PER_DAY - 1;
+
+// `effective_to` of the rates valid until the next one
+const OPEN_ENDED: StoreDate = StoreDate::MAX;
+
+// Decimal places kept by the fixed-point copy of the rates, see `ExactRate`
+const EXACT_RATE_SCALE: u32 = 18;
+
+// Most rows returned by the daily history and the series, see `kq_fx_get_rate_history` and
+// `kq_fx_rate_series`
+const MAX_SERIES_ROWS: usize = 1_000_000;
+
 // Default Queries
 
 const DEFAULT_Q1_VALIDATION_QUERY: &CStr = cr#"
@@ -24,45 +76,44 @@ const DEFAULT_Q1_VALIDATION_QUERY: &CStr = cr#"
     FROM
         information_schema.tables
     WHERE
-        table_schema = 'plan' AND (table_name = 'currency' or table_name = 'fx_rate')
+        table_schema = $1 AND (table_name = 'currency' or table_name = 'fx_rate')
 ;"#;
 
 const DEFAULT_Q2_GET_CURRENCIES_XUID_INIT: &CStr = cr#"
     SELECT
         cu.id, cu.xuid
     FROM
-        plan.currency cu
+        {rate_set}.currency cu
     ORDER BY
         cu.id ASC
 ;"#;
 
 const DEFAULT_Q3_GET_CURRENCY_ENTRIES: &CStr = cr#"
-    WITH
-        fx_rate AS (
-            SELECT
-                cr.currency_id,
-                cr.to_currency_id,
-                cr."date",
-                cr.rate,
-                ROW_NUMBER() OVER (PARTITION BY currency_id, to_currency_id ORDER BY "date" DESC) AS rn
-            FROM
-                plan.fx_rate cr
-            ORDER BY
-                1, 2, 3 DESC
-        )
     SELECT
-        currency_id,
-        to_currency_id,
-        date,
-        rate
+        cr.currency_id,
+        cr.to_currency_id,
+        cr."date",
+        cr.rate
     FROM
-        fx_rate
-    WHERE
-        rn <= 512
+        {rate_set}.fx_rate cr
     ORDER BY
         1, 2, 3
 ;"#;
 
+const DEFAULT_Q4_GET_PAIR_ENTRIES: &CStr = cr#"
+    SELECT
+        cr.currency_id,
+        cr.to_currency_id,
+        cr."date",
+        cr.rate
+    FROM
+        {rate_set}.fx_rate cr
+    WHERE
+        cr.currency_id = $2 AND cr.to_currency_id = $3
+    ORDER BY
+        3
+;"#;
+
 // Query GUCs
 
 static Q1_VALIDATION_QUERY: GucSetting<Option<&'static CStr>> =
@@ -74,6 +125,67 @@ static Q2_GET_CURRENCIES_XUID_INIT: GucSetting<Option<&'static CStr>> =
 static Q3_GET_CURRENCY_ENTRIES: GucSetting<Option<&'static CStr>> =
     GucSetting::<Option<&'static CStr>>::new(Some(DEFAULT_Q3_GET_CURRENCY_ENTRIES));
 
+static Q4_GET_PAIR_ENTRIES: GucSetting<Option<&'static CStr>> =
+    GucSetting::<Option<&'static CStr>>::new(Some(DEFAULT_Q4_GET_PAIR_ENTRIES));
+
+static Q5_GET_SCENARIO_SHOCKS: GucSetting<Option<&'static CStr>> =
+    GucSetting::<Option<&'static CStr>>::new(None);
+
+// Load limit GUCs, 0 is unlimited
+
+static MAX_CURRENCIES: GucSetting<i32> = GucSetting::<i32>::new(0);
+
+static MAX_ID_PAIRS: GucSetting<i32> = GucSetting::<i32>::new(0);
+
+static MAX_ENTRIES: GucSetting<i32> = GucSetting::<i32>::new(0);
+
+static OVERFLOW: GucSetting<OverflowPolicy> =
+    GucSetting::<OverflowPolicy>::new(OverflowPolicy::Error);
+
+// Lookup GUCs
+
+static RATE_SET: GucSetting<Option<&'static CStr>> =
+    GucSetting::<Option<&'static CStr>>::new(Some(c"plan"));
+
+static TIME_ZONE: GucSetting<Option<&'static CStr>> =
+    GucSetting::<Option<&'static CStr>>::new(Some(c"UTC"));
+
+static RATE_TYPE: GucSetting<Option<&'static CStr>> =
+    GucSetting::<Option<&'static CStr>>::new(None);
+
+static SCENARIO: GucSetting<Option<&'static CStr>> = GucSetting::<Option<&'static CStr>>::new(None);
+
+static PIVOT_CURRENCY_XUID: GucSetting<Option<&'static CStr>> =
+    GucSetting::<Option<&'static CStr>>::new(None);
+
+static INVERSE_FALLBACK: GucSetting<bool> = GucSetting::<bool>::new(false);
+
+static MAX_HOPS: GucSetting<i32> = GucSetting::<i32>::new(3);
+
+static EXTRAPOLATE: GucSetting<bool> = GucSetting::<bool>::new(false);
+
+static OUT_OF_RANGE: GucSetting<OutOfRangePolicy> =
+    GucSetting::<OutOfRangePolicy>::new(OutOfRangePolicy::Carry);
+
+static MAX_STALENESS_DAYS: GucSetting<i32> = GucSetting::<i32>::new(0);
+
+// Shared memory GUCs
+
+static MAX_CACHES: GucSetting<i32> = GucSetting::<i32>::new(32);
+
+// Load GUCs
+
+static LOAD_TIMEOUT: GucSetting<i32> = GucSetting::<i32>::new(60000);
+
+static EXACT_RATES: GucSetting<bool> = GucSetting::<bool>::new(false);
+
+// Preload worker GUCs
+
+static PRELOAD_DATABASE: GucSetting<Option<&'static CStr>> =
+    GucSetting::<Option<&'static CStr>>::new(None);
+
+static REFRESH_INTERVAL: GucSetting<i32> = GucSetting::<i32>::new(0);
+
 // Activate PostgreSQL Extension
 ::pgrx::pg_module_magic!();
 
@@ -83,513 +195,4280 @@ static Q3_GET_CURRENCY_ENTRIES: GucSetting<Option<&'static CStr>> =
 pub struct CurrencyControl {
     cache_filled: bool,
     cache_being_filled: bool,
+    /// PID of the backend filling the cache.
+    loader_pid: i32,
+    /// Marked stale while being filled, the load is then kept but reloaded by the next lookup.
+    cache_stale: bool,
+    /// Pairs re-queried by the next lookup, see `mark_pairs_stale`.
+    stale_pairs: heapless::Vec<FromToIdPair, MAX_STALE_PAIRS>,
 }
 
 unsafe impl PGRXSharedMemory for CurrencyControl {}
 
+/// Database and rate set of a cache, see `kq.currency.rate_set`.
+#[derive(Clone, Default, PartialEq)]
+pub struct CacheKey {
+    /// Database OID, 0 for unused slots.
+    database: u32,
+    rate_set: RateSetName,
+}
+
+impl CacheKey {
+    /// Key of a rate set in the current database, `None` when the name is too long.
+    fn new(rate_set: &str) -> Option<Self> {
+        Some(CacheKey {
+            database: unsafe { pg_sys::MyDatabaseId }.as_u32(),
+            rate_set: RateSetName::from_str(rate_set).ok()?,
+        })
+    }
+}
+
+/// Per cache shared state, indexed by `cache_slot`.
+#[derive(Clone)]
+pub struct CacheSlots<T> {
+    slots: [T; CACHE_SLOTS],
+}
+
+impl<T: Default> Default for CacheSlots<T> {
+    fn default() -> Self {
+        CacheSlots {
+            slots: std::array::from_fn(|_| T::default()),
+        }
+    }
+}
+
+unsafe impl<T> PGRXSharedMemory for CacheSlots<T> {}
+
+impl<T> Index<usize> for CacheSlots<T> {
+    type Output = T;
+
+    fn index(&self, slot: usize) -> &T {
+        &self.slots[slot]
+    }
+}
+
+impl<T> IndexMut<usize> for CacheSlots<T> {
+    fn index_mut(&mut self, slot: usize) -> &mut T {
+        &mut self.slots[slot]
+    }
+}
+
 // Types
 
 type PgDate = pgrx::datum::Date;
 type StoreDate = i32;
+/// Microseconds since midnight
+type StoreTime = i64;
+/// (DATE, TIME) of a rate in `kq.currency.time_zone`
+type StoreInstant = (StoreDate, StoreTime);
 type FromToIdPair = (i64, i64);
-type StoreDateRatePair = (StoreDate, f64);
+/// (FROM_CURRENCY_ID, TO_CURRENCY_ID, RATE_TYPE, SCENARIO)
+type RateKey = (i64, i64, RateTypeId, ScenarioId);
+type RateTypeId = i32;
+type RateTypeName = heapless::String<RATE_TYPE_MAX_LEN>;
+/// (SCENARIO, FROM_CURRENCY_ID, TO_CURRENCY_ID)
+type ShockKey = (ScenarioId, i64, i64);
+type ScenarioId = i32;
+type ScenarioName = heapless::String<SCENARIO_MAX_LEN>;
+/// Rate as a fixed-point decimal, `value * 10^EXACT_RATE_SCALE`.
+type ExactRate = i128;
 type CurrencyXuid = heapless::String<CURRENCY_XUID_MAX_LEN>;
-type CurrencyDataMap = heapless::FnvIndexMap<
-    FromToIdPair,
-    heapless::Vec<StoreDateRatePair, MAX_ENTRIES>,
-    MAX_ID_PAIRS,
->;
-type CurrencyXuidMap = heapless::FnvIndexMap<CurrencyXuid, i64, MAX_CURRENCIES>;
-
-// Shared Memory Structs
-
-static CURRENCY_CONTROL: PgLwLock<CurrencyControl> = PgLwLock::new();
-/// CURRENCY_ID => CURRENCY_XUID
-static CURRENCY_XUID_MAP: PgLwLock<CurrencyXuidMap> = PgLwLock::new();
-/// (FROM_CURRENCY_ID, TO_CURRENCY_ID) => (DATE, RATE)
-static CURRENCY_DATA_MAP: PgLwLock<CurrencyDataMap> = PgLwLock::new();
+type RateSetName = heapless::String<RATE_SET_MAX_LEN>;
+type CurrencyRateTypesMap<'a> = FrozenMapRef<'a, RateTypeName, RateTypeId>;
+type CurrencyScenariosMap<'a> = FrozenMapRef<'a, ScenarioName, ScenarioId>;
+type CurrencyXuidMap<'a> = FrozenMapRef<'a, CurrencyXuid, i64>;
+type CurrencyMinorUnitsMap<'a> = FrozenMapRef<'a, i64, i32>;
+/// Strategy used to get the rate at a date from a pair's entries vector.
+type RateFinder<T> = fn(&[StoreRateEntry], StoreInstant) -> Option<T>;
+type CurrencyGraph<'a> = FrozenVecMapRef<'a, i64, i64>;
 
-// Init Extension
+/// A cached rate. `exact_rate` keeps the source value as a fixed-point decimal, which is exact when
+/// the source column is `numeric`, for the numeric lookups. It is `None` when
+/// `kq.currency.exact_rates` is off or the value does not fit, the numeric lookups then use `rate`. `bid` and `ask` are the mid `rate` when
+/// the source has no quotes. The rate is not carried past `effective_to`, inclusive. `time` is `0`
+/// for the rates loaded from a date.
+#[derive(Clone, Copy, Debug)]
+struct StoreRateEntry {
+    date: StoreDate,
+    time: StoreTime,
+    rate: f64,
+    exact_rate: Option<ExactRate>,
+    bid: f64,
+    ask: f64,
+    effective_to: StoreDate,
+}
 
-#[pg_guard]
-pub extern "C" fn _PG_init() {
-    pg_shmem_init!(CURRENCY_CONTROL);
-    pg_shmem_init!(CURRENCY_XUID_MAP);
-    pg_shmem_init!(CURRENCY_DATA_MAP);
-    unsafe {
-        init_gucs();
+impl StoreRateEntry {
+    fn instant(&self) -> StoreInstant {
+        (self.date, self.time)
     }
-    info!("ketteQ FX Extension (kq_fx) Loaded");
 }
 
-#[pg_guard]
-pub extern "C" fn _PG_fini() {
-    info!("ketteQ FX Extension (kq_fx) Unloaded");
+/// Bid, mid and ask rates of a pair, see `kq_fx_get_quote`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Quote {
+    bid: f64,
+    mid: f64,
+    ask: f64,
 }
 
-unsafe fn init_gucs() {
-    GucRegistry::define_string_guc(
-        "kq.currency.q1_validation",
-        "Query to validate the current schema in order to create the ketteQ FX Currency Cache Extension.",
-        "",
-        &Q1_VALIDATION_QUERY,
-        GucContext::Suset,
-        GucFlags::empty()
-    );
-    GucRegistry::define_string_guc(
-        "kq.currency.q2_get_currencies_xuid",
-        "Query to get the currencies IDs and XUIDs.",
-        "",
-        &Q2_GET_CURRENCIES_XUID_INIT,
-        GucContext::Suset,
-        GucFlags::empty(),
-    );
-    GucRegistry::define_string_guc(
-        "kq.currency.q3_get_currency_entries",
-        "Query to actually get the currencies and store it in the shared memory cache.",
-        "",
-        &Q3_GET_CURRENCY_ENTRIES,
-        GucContext::Suset,
-        GucFlags::empty(),
-    );
-}
+impl ComposableRate for Quote {
+    fn one() -> Self {
+        Quote {
+            bid: 1.0,
+            mid: 1.0,
+            ask: 1.0,
+        }
+    }
 
-fn is_cache_filled() -> bool {
-    if CURRENCY_CONTROL.share().cache_filled {
-        return true;
+    /// The inverse bid is the inverse of the ask and the other way around.
+    fn inverse(&self) -> Option<Self> {
+        Some(Quote {
+            bid: self.ask.inverse()?,
+            mid: self.mid.inverse()?,
+            ask: self.bid.inverse()?,
+        })
     }
 
-    if CURRENCY_CONTROL.share().cache_being_filled {
-        while CURRENCY_CONTROL.share().cache_being_filled {
-            std::thread::sleep(Duration::from_millis(1));
+    fn compose(self, other: Self) -> Self {
+        Quote {
+            bid: self.bid * other.bid,
+            mid: self.mid * other.mid,
+            ask: self.ask * other.ask,
         }
-        return true;
     }
+}
 
-    false
+/// Side of the quote used by the conversion functions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum QuoteSide {
+    Bid,
+    Mid,
+    Ask,
 }
 
-// Cache management internals
-fn ensure_cache_populated() {
-    if is_cache_filled() {
-        return;
+impl QuoteSide {
+    fn from_name(side: &str) -> Self {
+        match side {
+            "bid" => QuoteSide::Bid,
+            "mid" => QuoteSide::Mid,
+            "ask" => QuoteSide::Ask,
+            _ => error!("invalid quote side: {side}, expected bid, mid or ask"),
+        }
     }
 
-    if let Err(msg) = validate_compatible_db() {
-        error!("{}", msg);
+    fn rate(&self, quote: Quote) -> f64 {
+        match self {
+            QuoteSide::Bid => quote.bid,
+            QuoteSide::Mid => quote.mid,
+            QuoteSide::Ask => quote.ask,
+        }
     }
+}
+
+/// Factor applied to the base rates of a pair in a scenario, see
+/// `kq.currency.q5_get_scenario_shocks`.
+#[derive(Clone, Copy, Debug)]
+struct ShockFactor {
+    rate: f64,
+    exact_rate: Option<ExactRate>,
+}
 
-    let mut xuid_map = CURRENCY_XUID_MAP.exclusive();
+/// Rate representations the lookups can compose through inverse and triangulated paths.
+trait ComposableRate: Sized {
+    fn one() -> Self;
+    /// `None` when the rate has no inverse (zero).
+    fn inverse(&self) -> Option<Self>;
+    fn compose(self, other: Self) -> Self;
+}
 
-    //someone else might have filled it already
-    if is_cache_filled() {
-        return;
+impl ComposableRate for f64 {
+    fn one() -> Self {
+        1.0
     }
 
-    CURRENCY_CONTROL.exclusive().cache_being_filled = true;
+    fn inverse(&self) -> Option<Self> {
+        if *self == 0.0 {
+            None
+        } else {
+            Some(1.0 / self)
+        }
+    }
 
-    // Init Currencies (id and xuid) & lock shmem maps
-    let mut data_map = CURRENCY_DATA_MAP.exclusive();
-    let mut currencies_count: i64 = 0;
-    Spi::connect(|client| {
-        let select = client.select(&get_guc_string(&Q2_GET_CURRENCIES_XUID_INIT), None, None);
-        match select {
-            Ok(tuple_table) => {
-                for row in tuple_table {
-                    let id = row[1]
-                        .value::<i64>()
-                        .unwrap_or_else(|err| error!("server interface error - {err}"))
-                        .unwrap_or_else(|| error!("cannot get currency_id"));
+    fn compose(self, other: Self) -> Self {
+        self * other
+    }
+}
 
-                    let xuid = row[2]
-                        .value::<String>()
-                        .unwrap_or_else(|err| error!("server interface error - {err}"))
-                        .unwrap_or_else(|| error!("cannot get currency_xuid"));
+impl ComposableRate for AnyNumeric {
+    fn one() -> Self {
+        AnyNumeric::from(1)
+    }
 
-                    let xuid_str =
-                        CurrencyXuid::from(heapless::String::from_str(xuid.as_str()).unwrap());
+    fn inverse(&self) -> Option<Self> {
+        if *self == AnyNumeric::from(0) {
+            None
+        } else {
+            Some(AnyNumeric::from(1) / self.clone())
+        }
+    }
 
-                    xuid_map.insert(xuid_str, id).unwrap();
+    fn compose(self, other: Self) -> Self {
+        self * other
+    }
+}
 
-                    currencies_count += 1;
+/// How a rate was resolved by the lookup functions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RatePath {
+    /// Same source and target currency, rate is always 1.
+    Identity,
+    /// The (from, to) pair was found in the cache.
+    Direct,
+    /// Computed as 1 / (to, from), see `kq.currency.inverse_fallback`.
+    Inverse,
+    /// Computed as (from, pivot) * (pivot, to), see `kq.currency.pivot_currency`.
+    Triangulated,
+    /// No rate available for the requested date.
+    NotFound,
+}
 
-                    debug2!("Currency initialized. ID: {}, xuid: {}", id, xuid)
-                }
-            }
-            Err(spi_error) => {
-                error!("Cannot load currencies. {}", spi_error)
-            }
+impl RatePath {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RatePath::Identity => "identity",
+            RatePath::Direct => "direct",
+            RatePath::Inverse => "inverse",
+            RatePath::Triangulated => "triangulated",
+            RatePath::NotFound => "not_found",
         }
-    });
+    }
+}
 
-    let mut entry_count: i64 = 0;
-    Spi::connect(|client| {
-        let select = client.select(&crate::get_guc_string(&Q3_GET_CURRENCY_ENTRIES), None, None);
-        match select {
-            Ok(tuple_table) => {
-                for row in tuple_table {
-                    let from_id = row[1]
-                        .value::<i64>()
-                        .unwrap_or_else(|err| error!("server interface error - {err}"))
-                        .unwrap_or_else(|| error!("cannot get from_id"));
+/// What the lookups return for dates outside the cached range of a pair.
+#[derive(Clone, Copy, Debug, PartialEq, PostgresGucEnum)]
+enum OutOfRangePolicy {
+    /// NULL before the first date, last rate after the last date.
+    Carry,
+    /// NULL before the first date and after the last date.
+    Null,
+    /// Like `Null`, but the lookup functions raise an error instead of returning NULL.
+    Error,
+    /// First rate before the first date, last rate after the last date.
+    Clamp,
+}
 
-                    let to_id = row[2]
-                        .value::<i64>()
-                        .unwrap_or_else(|err| error!("server interface error - {err}"))
-                        .unwrap_or_else(|| error!("cannot get to_id"));
+/// What a cache load does with data exceeding the `kq.currency.max_*` limits.
+#[derive(Clone, Copy, Debug, PartialEq, PostgresGucEnum)]
+enum OverflowPolicy {
+    /// Fail the load, naming the exceeded limit and the required size.
+    Error,
+    /// Keep the currencies and pairs with the lowest ids and the most recent entries of each pair,
+    /// logging a warning with the dropped counts.
+    Truncate,
+}
 
-                    let date = row[3]
-                        .value::<PgDate>()
-                        .unwrap_or_else(|err| error!("server interface error - {err}"))
-                        .unwrap_or_else(|| error!("cannot get date"));
+// Shared Memory Structs
 
-                    let rate: f64 = row[4]
-                        .value::<f64>()
-                        .unwrap_or_else(|err| error!("server interface error - {err}"))
-                        .unwrap_or_else(|| error!("cannot get rate"));
+/// Database and rate set of each slot
+static CURRENCY_KEYS: PgLwLock<CacheSlots<CacheKey>> = PgLwLock::new();
+static CURRENCY_CONTROL: PgLwLock<CacheSlots<CurrencyControl>> = PgLwLock::new();
+/// Broadcast when a cache load ends, successfully or not
+static CURRENCY_LOAD_CV: SharedConditionVariable =
+    SharedConditionVariable::new(c"kq_fx cache load");
+/// Dynamic shared memory holding the loaded cache
+static CURRENCY_AREA: SharedArea = SharedArea::new(c"kq_fx cache");
+/// Location of each loaded cache in CURRENCY_AREA
+static CURRENCY_CACHE: PgLwLock<CacheSlots<CurrencyCache>> = PgLwLock::new();
 
-                    let entry = (date.to_pg_epoch_days(), rate);
+/// Location of the frozen cache in `CURRENCY_AREA`, `None` until loaded.
+#[derive(Clone, Default)]
+pub struct CurrencyCache {
+    allocation: Option<SharedAllocation>,
+}
 
-                    match data_map.entry((from_id, to_id)) {
-                        Entry::Vacant(v) => {
-                            let mut new_data_vec: heapless::Vec<StoreDateRatePair, MAX_ENTRIES> =
-                                heapless::Vec::<StoreDateRatePair, MAX_ENTRIES>::new();
-                            new_data_vec.push(entry).unwrap();
-                            v.insert(new_data_vec).unwrap();
-                            debug2!("entries vector From_ID: {from_id}, To_ID: {to_id} created");
-                        }
-                        Entry::Occupied(mut o) => {
-                            let data_vec = o.get_mut();
-                            data_vec
-                                .push(entry)
-                                .unwrap_or_else(|e| error!("cannot insert more elements into (date, rate) vector, ({},{}, curr: {}, max: {})", e.0, e.1, data_vec.len(), data_vec.capacity()));
-                        }
-                    }
+unsafe impl PGRXSharedMemory for CurrencyCache {}
 
-                    entry_count += 1;
+/// Header of the frozen cache, the maps arrays follow it in the same allocation.
+#[repr(C)]
+struct CurrencyCacheLayout {
+    xuid_map: FrozenMap<CurrencyXuid, i64>,
+    minor_units_map: FrozenMap<i64, i32>,
+    data_map: FrozenVecMap<RateKey, StoreRateEntry>,
+    rate_types: FrozenMap<RateTypeName, RateTypeId>,
+    scenarios: FrozenMap<ScenarioName, ScenarioId>,
+    shocks: FrozenMap<ShockKey, ShockFactor>,
+    graph: FrozenVecMap<i64, i64>,
+}
 
-                    debug2!(
-                        "Inserted into shared cache: ({},{}) => ({}, {})",
-                        from_id,
-                        to_id,
-                        date,
-                        rate
-                    );
-                }
-            }
-            Err(spi_error) => {
-                error!("Cannot load currency rates. {}", spi_error)
-            }
-        }
-    });
+/// The cache maps, borrowed from a `CURRENCY_CACHE` lock guard.
+#[derive(Default)]
+struct CurrencyMaps<'a> {
+    /// CURRENCY_XUID => CURRENCY_ID
+    xuid_map: CurrencyXuidMap<'a>,
+    /// CURRENCY_ID => MINOR_UNITS
+    minor_units_map: CurrencyMinorUnitsMap<'a>,
+    /// (FROM_CURRENCY_ID, TO_CURRENCY_ID) => (DATE, RATE, EXACT_RATE) of the rate type and
+    /// scenario
+    data_map: CurrencyDataMap<'a>,
+    /// RATE_TYPE_NAME => RATE_TYPE, without the default rate type
+    rate_types: CurrencyRateTypesMap<'a>,
+    /// SCENARIO_NAME => SCENARIO, without the base scenario
+    scenarios: CurrencyScenariosMap<'a>,
+    /// CURRENCY_ID => ADJACENT CURRENCY_IDS (cached pairs in either direction)
+    graph: CurrencyGraph<'a>,
+}
 
-    // Ensure items are ordered ASC. Rq. for Binary Search.
-    /*CHECK IF NEEDED AS WE ARE LOADING SORTED DATA
-    for (_, data_vec) in data_map.iter_mut() {
-        data_vec.sort_by_key(|d| d.0)
+impl<'a> CurrencyMaps<'a> {
+    /// Binds `data_map` to a rate type, an empty name is the default rate type.
+    fn with_rate_type(mut self, rate_type: &str) -> Self {
+        self.data_map.rate_type = if rate_type.is_empty() {
+            Some(DEFAULT_RATE_TYPE)
+        } else {
+            RateTypeName::from_str(rate_type)
+                .ok()
+                .and_then(|rate_type| self.rate_types.get(&rate_type))
+                .copied()
+        };
+        self
     }
-    */
 
-    {
-        *CURRENCY_CONTROL.exclusive() = CurrencyControl {
-            cache_filled: true,
-            cache_being_filled: false,
+    /// Binds `data_map` to a scenario, an empty name is the base scenario. Scenarios that are
+    /// not cached have no rates of their own and read the base rates.
+    fn with_scenario(mut self, scenario: &str) -> Self {
+        self.data_map.scenario = if scenario.is_empty() {
+            None
+        } else {
+            ScenarioName::from_str(scenario)
+                .ok()
+                .and_then(|scenario| self.scenarios.get(&scenario))
+                .copied()
         };
+        self
     }
-
-    debug2!("Cache ready, entries: {entry_count}.");
 }
 
-fn get_guc_string(guc: &GucSetting<Option<&'static CStr>>) -> String {
-    let value = String::from_utf8_lossy(guc.get().expect("Cannot get GUC value.").to_bytes())
-        .to_string()
-        .replace('\n', " ");
-    debug2!("Query: {value}");
-    value
+/// The cached pairs entries of a single rate type and scenario.
+#[derive(Default)]
+struct CurrencyDataMap<'a> {
+    /// Entries of all the rate types and scenarios.
+    entries: FrozenVecMapRef<'a, RateKey, StoreRateEntry>,
+    /// Factors applied to the base rates of the scenarios.
+    shocks: FrozenMapRef<'a, ShockKey, ShockFactor>,
+    /// `None` when the rate type is not cached.
+    rate_type: Option<RateTypeId>,
+    /// `None` reads the base rates.
+    scenario: Option<ScenarioId>,
 }
 
-/// This method prevents using the extension in incompatible databases.
-fn validate_compatible_db() -> Result<(), String> {
-    let spi_result: SpiResult<Option<bool>> = Spi::get_one(&get_guc_string(&Q1_VALIDATION_QUERY));
-    match spi_result {
-        Ok(found_tables_opt) => match found_tables_opt {
-            None => Err(
-                "The current database is not compatible with the ketteQ FX extension.".to_string(),
-            ),
-            Some(valid) => {
-                if !valid {
-                    Err(
-                        "The current database is not compatible with the ketteQ FX extension."
-                            .to_string(),
-                    )
-                } else {
-                    Ok(())
-                }
+impl<'a> CurrencyDataMap<'a> {
+    /// Rate of a pair at `at`, `None` when the pair is not cached. The rates of the scenario
+    /// take precedence, the base rates are used when the scenario has no rate for the pair at
+    /// `at`, multiplied by the scenario factor of the pair.
+    fn find<T: ComposableRate>(
+        &self,
+        (from_id, to_id): FromToIdPair,
+        at: StoreInstant,
+        find: RateFinder<T>,
+    ) -> Option<Option<T>> {
+        let rate_type = self.rate_type?;
+        let scenario_entries = self
+            .scenario
+            .and_then(|scenario| self.entries.get(&(from_id, to_id, rate_type, scenario)));
+        let base_entries = self
+            .entries
+            .get(&(from_id, to_id, rate_type, BASE_SCENARIO));
+        if scenario_entries.is_none() && base_entries.is_none() {
+            return None;
+        }
+
+        if let Some(rate) = scenario_entries.and_then(|entries| find(entries, at)) {
+            return Some(Some(rate));
+        }
+        let rate = base_entries.and_then(|entries| find(entries, at));
+        let shock = self
+            .scenario
+            .and_then(|scenario| self.shocks.get(&(scenario, from_id, to_id)));
+        match (rate, shock) {
+            (Some(rate), Some(shock)) => {
+                // The factor as a single entry at `at`, so the finder returns it as `T`
+                let factor = StoreRateEntry {
+                    date: at.0,
+                    time: at.1,
+                    rate: shock.rate,
+                    exact_rate: shock.exact_rate,
+                    bid: shock.rate,
+                    ask: shock.rate,
+                    effective_to: OPEN_ENDED,
+                };
+                Some(find(&[factor], at).map(|factor| rate.compose(factor)))
             }
-        },
-        Err(spi_error) => Err(format!("Cannot validate current database. {}", spi_error)),
+            (rate, _) => Some(rate),
+        }
     }
-}
 
-// Exported Functions
+    /// Rates of a pair at the end of ASC ordered dates, as `find` with `find_rate`, walking the
+    /// cached entries once. `None` when the pair is not cached.
+    fn find_series(
+        &self,
+        (from_id, to_id): FromToIdPair,
+        dates: &[StoreDate],
+    ) -> Option<Vec<Option<f64>>> {
+        let rate_type = self.rate_type?;
+        let scenario_entries = self
+            .scenario
+            .and_then(|scenario| self.entries.get(&(from_id, to_id, rate_type, scenario)));
+        let base_entries = self
+            .entries
+            .get(&(from_id, to_id, rate_type, BASE_SCENARIO));
+        if scenario_entries.is_none() && base_entries.is_none() {
+            return None;
+        }
 
-#[pg_extern]
-fn kq_fx_check_db() -> String {
-    match validate_compatible_db() {
-        Ok(_) => "Database is compatible with the extension.".to_string(),
-        Err(error_msg) => error_msg,
+        let scenario_rates = scenario_entries.map(|entries| rates_at_dates(entries, dates));
+        let base_rates = base_entries.map(|entries| rates_at_dates(entries, dates));
+        let shock = self
+            .scenario
+            .and_then(|scenario| self.shocks.get(&(scenario, from_id, to_id)));
+        let rates = (0..dates.len()).map(|index| {
+            scenario_rates
+                .as_ref()
+                .and_then(|rates| rates[index])
+                .or_else(|| {
+                    let rate = base_rates.as_ref().and_then(|rates| rates[index]);
+                    match shock {
+                        Some(shock) => rate.map(|rate| rate.compose(shock.rate)),
+                        None => rate,
+                    }
+                })
+        });
+        Some(rates.collect())
     }
-}
 
-#[pg_extern]
-fn kq_fx_invalidate_cache() -> &'static str {
-    debug2!("Waiting for lock...");
-    let mut xuid_map = CURRENCY_XUID_MAP.exclusive();
+    /// Dates of the cached entries of a pair, with the ones of the scenario. `None` when the pair
+    /// is not cached.
+    fn dates(&self, (from_id, to_id): FromToIdPair) -> Option<BTreeSet<StoreDate>> {
+        let rate_type = self.rate_type?;
+        let scenario_entries = self
+            .scenario
+            .and_then(|scenario| self.entries.get(&(from_id, to_id, rate_type, scenario)));
+        let base_entries = self
+            .entries
+            .get(&(from_id, to_id, rate_type, BASE_SCENARIO));
+        if scenario_entries.is_none() && base_entries.is_none() {
+            return None;
+        }
 
-    for (_, data_vec) in CURRENCY_DATA_MAP.exclusive().iter_mut() {
-        data_vec.clear();
+        let entries = scenario_entries.into_iter().chain(base_entries).flatten();
+        Some(entries.map(|entry| entry.date).collect())
     }
+}
 
-    CURRENCY_DATA_MAP.exclusive().clear();
+impl CurrencyCache {
+    /// Empty maps when the cache is not loaded. `data_map` holds the rate type and scenario, see
+    /// `CurrencyMaps::with_rate_type` and `CurrencyMaps::with_scenario`.
+    fn maps(&self, rate_type: &str, scenario: &str) -> CurrencyMaps<'_> {
+        let Some(allocation) = self.allocation else {
+            return CurrencyMaps::default();
+        };
+        let base = CURRENCY_AREA.address(allocation);
+        unsafe {
+            let layout = &*(base as *const CurrencyCacheLayout);
+            CurrencyMaps {
+                xuid_map: layout.xuid_map.get(base),
+                minor_units_map: layout.minor_units_map.get(base),
+                data_map: CurrencyDataMap {
+                    entries: layout.data_map.get(base),
+                    shocks: layout.shocks.get(base),
+                    rate_type: None,
+                    scenario: None,
+                },
+                rate_types: layout.rate_types.get(base),
+                scenarios: layout.scenarios.get(base),
+                graph: layout.graph.get(base),
+            }
+            .with_rate_type(rate_type)
+            .with_scenario(scenario)
+        }
+    }
+}
+
+/// The cache being loaded, kept in backend memory until frozen into `CURRENCY_AREA`.
+#[derive(Default)]
+struct CurrencyCacheBuilder {
+    xuid_map: BTreeMap<CurrencyXuid, i64>,
+    minor_units_map: BTreeMap<i64, i32>,
+    data_map: BTreeMap<RateKey, Vec<StoreRateEntry>>,
+    rate_types: BTreeMap<RateTypeName, RateTypeId>,
+    scenarios: BTreeMap<ScenarioName, ScenarioId>,
+    shocks: BTreeMap<ShockKey, ShockFactor>,
+    graph: BTreeMap<i64, Vec<i64>>,
+}
+
+impl CurrencyCacheBuilder {
+    /// Copies the maps of a loaded cache.
+    fn thaw(maps: &CurrencyMaps) -> Self {
+        CurrencyCacheBuilder {
+            xuid_map: maps.xuid_map.iter().map(|(k, v)| (k.clone(), *v)).collect(),
+            minor_units_map: maps.minor_units_map.iter().map(|(k, v)| (*k, *v)).collect(),
+            data_map: maps
+                .data_map
+                .entries
+                .iter()
+                .map(|(k, v)| (*k, v.to_vec()))
+                .collect(),
+            rate_types: maps
+                .rate_types
+                .iter()
+                .map(|(k, v)| (k.clone(), *v))
+                .collect(),
+            scenarios: maps
+                .scenarios
+                .iter()
+                .map(|(k, v)| (k.clone(), *v))
+                .collect(),
+            shocks: maps.data_map.shocks.iter().map(|(k, v)| (*k, *v)).collect(),
+            graph: maps.graph.iter().map(|(k, v)| (*k, v.to_vec())).collect(),
+        }
+    }
+
+    /// Id of a rate type, added when not known. An empty name is the default rate type.
+    fn rate_type_id(&mut self, rate_type: &str) -> RateTypeId {
+        if rate_type.is_empty() {
+            return DEFAULT_RATE_TYPE;
+        }
+        name_id(&mut self.rate_types, rate_type, "rate type")
+    }
+
+    /// Id of a scenario, added when not known. An empty name is the base scenario.
+    fn scenario_id(&mut self, scenario: &str) -> ScenarioId {
+        if scenario.is_empty() {
+            return BASE_SCENARIO;
+        }
+        name_id(&mut self.scenarios, scenario, "scenario")
+    }
+
+    /// Rebuilds the adjacency used by the shortest path lookup, pairs are linked in both
+    /// directions so the inverse fallback can be applied while walking the graph. Pairs of all
+    /// the rate types and scenarios are linked.
+    fn link_graph(&mut self) {
+        self.graph.clear();
+        for &(from_id, to_id, _, _) in self.data_map.keys() {
+            add_graph_edge(&mut self.graph, from_id, to_id);
+            add_graph_edge(&mut self.graph, to_id, from_id);
+        }
+    }
+
+    /// Orders the entries of each pair by point in time, as the lookups binary search them. The
+    /// sort is stable, rows at the same point in time keep the query order.
+    fn sort_entries(&mut self) {
+        for data_vec in self.data_map.values_mut() {
+            data_vec.sort_by_key(StoreRateEntry::instant);
+        }
+    }
+
+    /// Copies the maps into a new allocation of `CURRENCY_AREA` sized to fit them.
+    fn freeze(&self) -> SharedAllocation {
+        let mut layout = FrozenLayout::new::<CurrencyCacheLayout>();
+        let header = CurrencyCacheLayout {
+            xuid_map: FrozenMap::reserve(&mut layout, &self.xuid_map),
+            minor_units_map: FrozenMap::reserve(&mut layout, &self.minor_units_map),
+            data_map: FrozenVecMap::reserve(&mut layout, &self.data_map),
+            rate_types: FrozenMap::reserve(&mut layout, &self.rate_types),
+            scenarios: FrozenMap::reserve(&mut layout, &self.scenarios),
+            shocks: FrozenMap::reserve(&mut layout, &self.shocks),
+            graph: FrozenVecMap::reserve(&mut layout, &self.graph),
+        };
+        let allocation = CURRENCY_AREA.allocate(layout.size());
+        let base = CURRENCY_AREA.address(allocation);
+        unsafe {
+            header.xuid_map.write(base, &self.xuid_map);
+            header.minor_units_map.write(base, &self.minor_units_map);
+            header.data_map.write(base, &self.data_map);
+            header.rate_types.write(base, &self.rate_types);
+            header.scenarios.write(base, &self.scenarios);
+            header.shocks.write(base, &self.shocks);
+            header.graph.write(base, &self.graph);
+            std::ptr::write(base as *mut CurrencyCacheLayout, header);
+        }
+        debug2!("Cache frozen, size: {} bytes.", layout.size());
+        allocation
+    }
+}
+
+/// Id of a rate type or scenario name, assigning the next one when not known.
+fn name_id<const N: usize>(
+    ids: &mut BTreeMap<heapless::String<N>, i32>,
+    name: &str,
+    kind: &str,
+) -> i32 {
+    let name = heapless::String::<N>::from_str(name)
+        .unwrap_or_else(|_| error!("{kind} name exceeds {N} characters: {name}"));
+    let next_id = ids.len() as i32 + 1;
+    *ids.entry(name).or_insert(next_id)
+}
+
+/// Value of a load limit GUC, `i32::MAX` when unlimited.
+fn get_limit(guc: &GucSetting<i32>) -> i32 {
+    match guc.get() {
+        0 => i32::MAX,
+        limit => limit,
+    }
+}
+
+// Init Extension
+
+#[pg_guard]
+pub extern "C" fn _PG_init() {
+    pg_shmem_init!(CURRENCY_KEYS);
+    pg_shmem_init!(CURRENCY_CONTROL);
+    pg_shmem_init!(CURRENCY_LOAD_CV);
+    pg_shmem_init!(CURRENCY_AREA);
+    pg_shmem_init!(CURRENCY_CACHE);
+    unsafe {
+        init_gucs();
+    }
+    if PRELOAD_DATABASE
+        .get()
+        .is_some_and(|database| !database.is_empty())
+    {
+        BackgroundWorkerBuilder::new("kq_fx cache preload")
+            .set_library("kq_fx")
+            .set_function("kq_fx_preload_main")
+            .enable_spi_access()
+            .set_restart_time(Some(PRELOAD_RESTART_INTERVAL))
+            .load();
+    }
+    info!("ketteQ FX Extension (kq_fx) Loaded");
+}
+
+#[pg_guard]
+pub extern "C" fn _PG_fini() {
+    info!("ketteQ FX Extension (kq_fx) Unloaded");
+}
+
+/// Preload worker, loads the cache of `kq.currency.preload_database` and refreshes it every
+/// `kq.currency.refresh_interval`. A failed load exits, the worker is started again after
+/// `PRELOAD_RESTART_INTERVAL`.
+#[pg_guard]
+#[no_mangle]
+pub extern "C" fn kq_fx_preload_main(_arg: pg_sys::Datum) {
+    BackgroundWorker::attach_signal_handlers(SignalWakeFlags::SIGHUP | SignalWakeFlags::SIGTERM);
+    let database = PRELOAD_DATABASE
+        .get()
+        .map(|database| database.to_string_lossy().to_string())
+        .unwrap_or_default();
+    BackgroundWorker::connect_worker_to_spi(Some(&database), None);
+
+    BackgroundWorker::transaction(ensure_cache_populated);
+    log!("kq_fx cache preloaded, database: {database}");
+
+    let mut refreshed = Instant::now();
+    loop {
+        let interval = Duration::from_secs(REFRESH_INTERVAL.get() as u64);
+        let timeout = (!interval.is_zero()).then(|| interval.saturating_sub(refreshed.elapsed()));
+        if !BackgroundWorker::wait_latch(timeout) {
+            break;
+        }
+        // woken up by SIGHUP, the interval may have changed
+        if interval.is_zero() || refreshed.elapsed() < interval {
+            continue;
+        }
+        let result = BackgroundWorker::transaction(kq_fx_refresh_cache);
+        debug1!("kq_fx cache refresh: {result}");
+        refreshed = Instant::now();
+    }
+}
+
+unsafe fn init_gucs() {
+    GucRegistry::define_string_guc(
+        "kq.currency.q1_validation",
+        "Query to validate the current schema in order to create the ketteQ FX Currency Cache Extension.",
+        "Takes the rate set name as $1.",
+        &Q1_VALIDATION_QUERY,
+        GucContext::Suset,
+        GucFlags::empty()
+    );
+    GucRegistry::define_string_guc(
+        "kq.currency.q2_get_currencies_xuid",
+        "Query to get the currencies IDs and XUIDs.",
+        "An optional third column holds the decimal places used to round converted amounts, 2 when NULL.",
+        &Q2_GET_CURRENCIES_XUID_INIT,
+        GucContext::Suset,
+        GucFlags::empty(),
+    );
+    GucRegistry::define_string_guc(
+        "kq.currency.q3_get_currency_entries",
+        "Query to actually get the currencies and store it in the shared memory cache.",
+        "Returns (currency_id, to_currency_id, date, rate) rows, the optional rate_type, scenario, bid, ask and effective_to columns are matched by name.",
+        &Q3_GET_CURRENCY_ENTRIES,
+        GucContext::Suset,
+        GucFlags::empty(),
+    );
+    GucRegistry::define_string_guc(
+        "kq.currency.q4_get_pair_entries",
+        "Query to get the entries of a single currency pair when refreshing it.",
+        "Takes the (from, to) currency ids as $2 and $3, returns the same columns as kq.currency.q3_get_currency_entries.",
+        &Q4_GET_PAIR_ENTRIES,
+        GucContext::Suset,
+        GucFlags::empty(),
+    );
+    GucRegistry::define_string_guc(
+        "kq.currency.q5_get_scenario_shocks",
+        "Query to get the factors applied to the base rates of the scenarios.",
+        "Returns (scenario, currency_id, to_currency_id, factor) rows, empty (default) loads no factors.",
+        &Q5_GET_SCENARIO_SHOCKS,
+        GucContext::Suset,
+        GucFlags::empty(),
+    );
+    GucRegistry::define_int_guc(
+        "kq.currency.max_currencies",
+        "Maximum number of currencies loaded into the cache.",
+        "See kq.currency.overflow. 0 is unlimited.",
+        &MAX_CURRENCIES,
+        0,
+        i32::MAX,
+        GucContext::Suset,
+        GucFlags::empty(),
+    );
+    GucRegistry::define_int_guc(
+        "kq.currency.max_pairs",
+        "Maximum number of (from, to) currency pairs loaded into the cache.",
+        "See kq.currency.overflow. 0 is unlimited.",
+        &MAX_ID_PAIRS,
+        0,
+        i32::MAX,
+        GucContext::Suset,
+        GucFlags::empty(),
+    );
+    GucRegistry::define_int_guc(
+        "kq.currency.max_entries",
+        "Maximum number of (date, rate) entries loaded per currency pair.",
+        "See kq.currency.overflow. 0 is unlimited. Passed as $1 to kq.currency.q3_get_currency_entries, rows a custom query skips with it are not reported.",
+        &MAX_ENTRIES,
+        0,
+        i32::MAX,
+        GucContext::Suset,
+        GucFlags::empty(),
+    );
+    GucRegistry::define_enum_guc(
+        "kq.currency.overflow",
+        "What a cache load does with data exceeding the kq.currency.max_* limits: error or truncate.",
+        "error: fail the load naming the exceeded limit and the required size. truncate: keep the currencies and pairs with the lowest ids and the most recent entries of each pair, and log a warning with the dropped counts.",
+        &OVERFLOW,
+        GucContext::Suset,
+        GucFlags::empty(),
+    );
+    GucRegistry::define_int_guc(
+        "kq.currency.max_caches",
+        "Maximum number of caches kept by the server, one per database and rate set.",
+        "The slots of dropped databases are reused when all are taken.",
+        &MAX_CACHES,
+        1,
+        CACHE_SLOTS as i32,
+        GucContext::Postmaster,
+        GucFlags::empty(),
+    );
+    GucRegistry::define_int_guc(
+        "kq.currency.load_timeout",
+        "Maximum time to wait for another backend loading the cache.",
+        "The lookup fails when exceeded. 0 waits indefinitely.",
+        &LOAD_TIMEOUT,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::UNIT_MS,
+    );
+    GucRegistry::define_bool_guc(
+        "kq.currency.exact_rates",
+        "Keep a fixed-point decimal copy of the loaded rates for the numeric lookups.",
+        "Rates that do not fit and all the rates when disabled are converted from float8 by their shortest decimal form. Refresh the cache after changing it.",
+        &EXACT_RATES,
+        GucContext::Suset,
+        GucFlags::empty(),
+    );
+    GucRegistry::define_string_guc(
+        "kq.currency.preload_database",
+        "Database whose cache is loaded by a background worker at server start.",
+        "Empty disables the worker.",
+        &PRELOAD_DATABASE,
+        GucContext::Postmaster,
+        GucFlags::empty(),
+    );
+    GucRegistry::define_int_guc(
+        "kq.currency.refresh_interval",
+        "Interval between cache refreshes by the preload worker.",
+        "See kq.currency.preload_database. 0 disables the refresh.",
+        &REFRESH_INTERVAL,
+        0,
+        i32::MAX,
+        GucContext::Sighup,
+        GucFlags::UNIT_S,
+    );
+    GucRegistry::define_string_guc(
+        "kq.currency.rate_set",
+        "Schema holding the currency tables read by the lookups.",
+        "Replaces {rate_set} in the cache queries, each rate set is cached separately.",
+        &RATE_SET,
+        GucContext::Suset,
+        GucFlags::empty(),
+    );
+    GucRegistry::define_string_guc(
+        "kq.currency.time_zone",
+        "Time zone of the cached rates.",
+        "Loaded timestamptz rates are shifted to it and the date lookups read the rates in effect at the end of the day in it. Refresh the cache after changing it.",
+        &TIME_ZONE,
+        GucContext::Sighup,
+        GucFlags::empty(),
+    );
+    GucRegistry::define_string_guc(
+        "kq.currency.rate_type",
+        "Rate type read by the lookups.",
+        "Matches the optional rate_type column of the entries query, empty (default) reads the rows without a rate type.",
+        &RATE_TYPE,
+        GucContext::Userset,
+        GucFlags::empty(),
+    );
+    GucRegistry::define_string_guc(
+        "kq.currency.scenario",
+        "Scenario read by the lookups.",
+        "Pairs and dates without rates in the scenario fall back to the base rates, empty (default) reads the base rates.",
+        &SCENARIO,
+        GucContext::Userset,
+        GucFlags::empty(),
+    );
+    GucRegistry::define_string_guc(
+        "kq.currency.pivot_currency",
+        "XUID of the currency used to triangulate pairs that are not cached directly.",
+        "When set, a missing (from, to) pair is computed as (from, pivot) * (pivot, to) using the rates of both legs at the same date. Empty disables triangulation.",
+        &PIVOT_CURRENCY_XUID,
+        GucContext::Userset,
+        GucFlags::empty(),
+    );
+    GucRegistry::define_bool_guc(
+        "kq.currency.inverse_fallback",
+        "Use 1 / (to, from) when only the opposite pair is cached.",
+        "A zero opposite rate has no inverse and is reported as not found.",
+        &INVERSE_FALLBACK,
+        GucContext::Userset,
+        GucFlags::empty(),
+    );
+    GucRegistry::define_int_guc(
+        "kq.currency.max_hops",
+        "Maximum number of conversions chained by kq_fx_get_rate_path.",
+        "",
+        &MAX_HOPS,
+        1,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::empty(),
+    );
+    GucRegistry::define_bool_guc(
+        "kq.currency.extrapolate",
+        "Extrapolate interpolated rates outside the cached date range.",
+        "When disabled kq_fx_get_rate_interpolated returns NULL before the first date and the last rate after the last date.",
+        &EXTRAPOLATE,
+        GucContext::Userset,
+        GucFlags::empty(),
+    );
+    GucRegistry::define_enum_guc(
+        "kq.currency.out_of_range",
+        "Rate returned for dates outside the cached range of a pair: carry, null, error or clamp.",
+        "carry: NULL before the first date and last rate after the last date. null: NULL on both ends. error: raise an error whenever no rate is available. clamp: first rate before the first date and last rate after the last date.",
+        &OUT_OF_RANGE,
+        GucContext::Userset,
+        GucFlags::empty(),
+    );
+    GucRegistry::define_int_guc(
+        "kq.currency.max_staleness_days",
+        "Rates carried forward for more than this number of days are treated as missing.",
+        "0 disables the check.",
+        &MAX_STALENESS_DAYS,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::empty(),
+    );
+}
+
+/// Key of the cache of the current database and rate set.
+fn cache_key() -> CacheKey {
+    let rate_set = get_rate_set();
+    if rate_set.is_empty() {
+        error!("kq.currency.rate_set is not set");
+    }
+    CacheKey::new(rate_set).unwrap_or_else(|| {
+        error!("rate set name exceeds {RATE_SET_MAX_LEN} characters: {rate_set}")
+    })
+}
+
+/// Slot of a cache in `CURRENCY_CONTROL` and `CURRENCY_CACHE`, assigned to validated rate sets
+/// only and kept until the cache is invalidated or its database dropped, see
+/// `ensure_cache_populated`.
+fn assign_cache_slot(key: &CacheKey) -> usize {
+    if let Some(slot) = take_cache_slot(key) {
+        return slot;
+    }
+    if release_dropped_caches() {
+        if let Some(slot) = take_cache_slot(key) {
+            return slot;
+        }
+    }
+    let max_caches = MAX_CACHES.get();
+    error!("cannot cache more than {max_caches} rate sets, see kq.currency.max_caches")
+}
+
+/// Slot of a key, taking the first free one of the `kq.currency.max_caches` slots when it has
+/// none. `None` when all are taken.
+fn take_cache_slot(key: &CacheKey) -> Option<usize> {
+    let mut keys = CURRENCY_KEYS.exclusive();
+    if let Some(slot) = keys.slots.iter().position(|k| k == key) {
+        return Some(slot);
+    }
+    let max_caches = MAX_CACHES.get() as usize;
+    let slot = keys.slots[..max_caches]
+        .iter()
+        .position(|k| k.database == 0)?;
+    keys[slot] = key.clone();
+    Some(slot)
+}
+
+/// Frees the caches of the databases dropped since they were loaded and releases their slots.
+/// Returns `false` when there are none.
+fn release_dropped_caches() -> bool {
+    // The catalog is read without holding the locks
+    let databases: BTreeSet<u32> = CURRENCY_KEYS
+        .share()
+        .slots
+        .iter()
+        .map(|key| key.database)
+        .filter(|&database| database != 0)
+        .collect();
+    let dropped: BTreeSet<u32> = databases
+        .into_iter()
+        .filter(|&database| unsafe {
+            !pg_sys::SearchSysCacheExists(
+                pg_sys::SysCacheIdentifier::DATABASEOID as i32,
+                database.into(),
+                0.into(),
+                0.into(),
+                0.into(),
+            )
+        })
+        .collect();
+    if dropped.is_empty() {
+        return false;
+    }
+
+    let mut caches = CURRENCY_CACHE.exclusive();
+    let mut control = CURRENCY_CONTROL.exclusive();
+    let mut keys = CURRENCY_KEYS.exclusive();
+    for slot in 0..CACHE_SLOTS {
+        if dropped.contains(&keys[slot].database) {
+            if let Some(allocation) = caches[slot].allocation.take() {
+                CURRENCY_AREA.free(allocation);
+            }
+            control[slot] = CurrencyControl::default();
+            keys[slot] = CacheKey::default();
+        }
+    }
+    debug1!("kq_fx caches of dropped databases released: {dropped:?}");
+    true
+}
+
+/// Slot of a rate set of the current database, `None` when it has not been assigned.
+fn find_cache_slot(rate_set: &str) -> Option<usize> {
+    key_cache_slot(&CacheKey::new(rate_set)?)
+}
+
+fn key_cache_slot(key: &CacheKey) -> Option<usize> {
+    CURRENCY_KEYS.share().slots.iter().position(|k| k == key)
+}
+
+/// Maps of the cache of a slot from `ensure_cache_populated`, bound to the rate type and scenario
+/// or those of `kq.currency.rate_type` and `kq.currency.scenario`. Empty when the slot was
+/// released meanwhile, it is checked while `caches` is locked so it cannot be reused by another
+/// rate set.
+fn slot_maps<'a>(
+    caches: &'a CacheSlots<CurrencyCache>,
+    slot: usize,
+    rate_type: Option<&str>,
+    scenario: Option<&str>,
+) -> CurrencyMaps<'a> {
+    if CacheKey::new(get_rate_set()).is_none_or(|key| CURRENCY_KEYS.share()[slot] != key) {
+        return CurrencyMaps::default();
+    }
+    caches[slot].maps(
+        rate_type.unwrap_or(get_rate_type()),
+        scenario.unwrap_or(get_scenario()),
+    )
+}
+
+/// Replaces the loaded cache of a slot, `current` when the load started, freeing it. Returns
+/// `false`, freeing `allocation` instead, when the slot was released by an invalidation or the
+/// cache replaced by another load meanwhile.
+fn swap_cache(
+    slot: usize,
+    key: &CacheKey,
+    current: Option<SharedAllocation>,
+    allocation: SharedAllocation,
+) -> bool {
+    let mut caches = CURRENCY_CACHE.exclusive();
+    if caches[slot].allocation != current || CURRENCY_KEYS.share()[slot] != *key {
+        CURRENCY_AREA.free(allocation);
+        return false;
+    }
+    caches[slot].allocation = Some(allocation);
+    if let Some(previous) = current {
+        CURRENCY_AREA.free(previous);
+    }
+    true
+}
+
+fn get_rate_set() -> &'static str {
+    RATE_SET
+        .get()
+        .and_then(|rate_set| rate_set.to_str().ok())
+        .unwrap_or_default()
+}
+
+fn get_time_zone() -> &'static str {
+    TIME_ZONE
+        .get()
+        .and_then(|time_zone| time_zone.to_str().ok())
+        .unwrap_or_default()
+}
+
+fn get_rate_type() -> &'static str {
+    RATE_TYPE
+        .get()
+        .and_then(|rate_type| rate_type.to_str().ok())
+        .unwrap_or_default()
+}
+
+fn get_scenario() -> &'static str {
+    SCENARIO
+        .get()
+        .and_then(|scenario| scenario.to_str().ok())
+        .unwrap_or_default()
+}
+
+fn is_cache_filled(slot: usize) -> bool {
+    if CURRENCY_CONTROL.share()[slot].cache_filled {
+        return true;
+    }
+
+    if CURRENCY_CONTROL.share()[slot].cache_being_filled {
+        wait_for_cache_load(slot);
+        return CURRENCY_CONTROL.share()[slot].cache_filled;
+    }
+
+    false
+}
+
+/// Waits for the backend filling the cache, up to `kq.currency.load_timeout`. The flags are reset
+/// when that backend exited without doing it.
+fn wait_for_cache_load(slot: usize) {
+    let timeout = Duration::from_millis(LOAD_TIMEOUT.get() as u64);
+    let started = Instant::now();
+    loop {
+        let loader_pid = {
+            let control = &CURRENCY_CONTROL.share()[slot];
+            if !control.cache_being_filled {
+                break;
+            }
+            control.loader_pid
+        };
+
+        if unsafe { pg_sys::BackendPidGetProc(loader_pid) }.is_null() {
+            let control = &mut CURRENCY_CONTROL.exclusive()[slot];
+            if control.cache_being_filled && control.loader_pid == loader_pid {
+                warning!("backend {loader_pid} exited while loading the cache");
+                *control = CurrencyControl::default();
+                CURRENCY_LOAD_CV.broadcast();
+            }
+            break;
+        }
+
+        let sleep = if timeout.is_zero() {
+            LOADER_CHECK_INTERVAL
+        } else {
+            match timeout.checked_sub(started.elapsed()) {
+                Some(remaining) if !remaining.is_zero() => remaining.min(LOADER_CHECK_INTERVAL),
+                _ => error!(
+                    "timed out after {} ms waiting for backend {loader_pid} to load the cache",
+                    timeout.as_millis()
+                ),
+            }
+        };
+        CURRENCY_LOAD_CV.sleep(sleep, cache_load_wait_event());
+    }
+    CURRENCY_LOAD_CV.cancel_sleep();
+}
+
+/// Wait event reported by backends waiting for a cache load, `KqFxCacheLoad` from PostgreSQL 17
+/// and `Extension` before.
+fn cache_load_wait_event() -> u32 {
+    #[cfg(feature = "pg17")]
+    {
+        static WAIT_EVENT: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+        let mut wait_event = WAIT_EVENT.load(std::sync::atomic::Ordering::Relaxed);
+        if wait_event == 0 {
+            wait_event = unsafe { pg_sys::WaitEventExtensionNew(c"KqFxCacheLoad".as_ptr()) };
+            WAIT_EVENT.store(wait_event, std::sync::atomic::Ordering::Relaxed);
+        }
+        wait_event
+    }
+    #[cfg(not(feature = "pg17"))]
+    pg_sys::PG_WAIT_EXTENSION
+}
+
+// Cache management internals
+
+/// Slot of the cache of the current rate set, loading it when it is not loaded.
+fn ensure_cache_populated() -> usize {
+    let key = cache_key();
+    if let Some(slot) = key_cache_slot(&key) {
+        let (filled, stale) = {
+            let control = &CURRENCY_CONTROL.share()[slot];
+            (control.cache_filled, !control.stale_pairs.is_empty())
+        };
+        if filled && !stale {
+            return slot;
+        }
+        if filled || is_cache_filled(slot) {
+            refresh_stale_pairs(slot, &key);
+            return slot;
+        }
+    }
+
+    // Validated before taking a slot, so unknown rate sets cannot use them up
+    if let Err(msg) = validate_compatible_db() {
+        error!("{}", msg);
+    }
+
+    // Someone else might have filled it already, lookups of other databases are not blocked by
+    // the load.
+    let slot = loop {
+        let slot = assign_cache_slot(&key);
+        {
+            let control = &mut CURRENCY_CONTROL.exclusive()[slot];
+            // released by an invalidation meanwhile
+            if CURRENCY_KEYS.share()[slot] != key {
+                continue;
+            }
+            if control.cache_filled {
+                return slot;
+            }
+            if !control.cache_being_filled {
+                *control = CurrencyControl {
+                    cache_being_filled: true,
+                    loader_pid: unsafe { pg_sys::MyProcPid },
+                    ..CurrencyControl::default()
+                };
+                break slot;
+            }
+        }
+        if is_cache_filled(slot) {
+            return slot;
+        }
+    };
+
+    let current = CURRENCY_CACHE.share()[slot].allocation;
+    // On any error the loaded maps are dropped and the flags reset, so the next lookup retries.
+    let allocation = PgTryBuilder::new(|| with_latest_snapshot(load_cache))
+        .catch_others(|error| {
+            CURRENCY_CONTROL.exclusive()[slot] = CurrencyControl::default();
+            CURRENCY_LOAD_CV.broadcast();
+            error.rethrow()
+        })
+        .execute();
+
+    // the slot is not released while being filled, a pairs reload or an invalidation meanwhile
+    // leaves the cache to the next lookup
+    let swapped = swap_cache(slot, &key, current, allocation);
+
+    {
+        let control = &mut CURRENCY_CONTROL.exclusive()[slot];
+        *control = CurrencyControl {
+            cache_filled: swapped && !control.cache_stale,
+            ..CurrencyControl::default()
+        };
+    }
+    CURRENCY_LOAD_CV.broadcast();
+    slot
+}
+
+/// Marks a cache stale, the next lookup in any backend reloads it.
+fn mark_cache_stale(slot: usize) {
+    let control = &mut CURRENCY_CONTROL.exclusive()[slot];
+    control.cache_filled = false;
+    control.cache_stale = control.cache_being_filled;
+}
+
+/// Marks the pairs stale, the next lookup in any backend re-queries them. The whole cache is marked
+/// stale when it is not loaded or more than `MAX_STALE_PAIRS` pairs are stale.
+fn mark_pairs_stale(slot: usize, pairs: BTreeSet<FromToIdPair>) {
+    {
+        let control = &mut CURRENCY_CONTROL.exclusive()[slot];
+        if control.cache_filled
+            && pairs.into_iter().all(|pair| {
+                control.stale_pairs.contains(&pair) || control.stale_pairs.push(pair).is_ok()
+            })
+        {
+            return;
+        }
+    }
+    mark_cache_stale(slot);
+}
+
+/// Re-queries the pairs marked stale, the whole cache is marked stale when it fails.
+fn refresh_stale_pairs(slot: usize, key: &CacheKey) {
+    if CURRENCY_CONTROL.share()[slot].stale_pairs.is_empty() {
+        return;
+    }
+
+    let stale_pairs = std::mem::take(&mut CURRENCY_CONTROL.exclusive()[slot].stale_pairs);
+    // refreshed by another backend meanwhile
+    if stale_pairs.is_empty() {
+        return;
+    }
+
+    PgTryBuilder::new(|| reload_pairs(slot, key, &stale_pairs))
+        .catch_others(|error| {
+            mark_cache_stale(slot);
+            error.rethrow()
+        })
+        .execute();
+}
+
+/// Copies the cache with the pairs re-queried, see `kq.currency.q4_get_pair_entries`. Lookups keep
+/// reading the current cache until the copy replaces it. When the cache was replaced meanwhile the
+/// copy is dropped and the pairs are marked stale again.
+fn reload_pairs(slot: usize, key: &CacheKey, pairs: &[FromToIdPair]) {
+    let (mut builder, current) = {
+        let caches = CURRENCY_CACHE.share();
+        (
+            CurrencyCacheBuilder::thaw(&caches[slot].maps("", "")),
+            caches[slot].allocation,
+        )
+    };
+
+    let query = get_guc_string(&Q4_GET_PAIR_ENTRIES);
+    with_latest_snapshot(|| {
+        for &(from_id, to_id) in pairs {
+            builder
+                .data_map
+                .retain(|&(from, to, _, _), _| (from, to) != (from_id, to_id));
+            let entry_count = load_entries(
+                &mut builder,
+                &query,
+                vec![
+                    (
+                        PgBuiltInOids::INT4OID.oid(),
+                        get_limit(&MAX_ENTRIES).into_datum(),
+                    ),
+                    (PgBuiltInOids::INT8OID.oid(), from_id.into_datum()),
+                    (PgBuiltInOids::INT8OID.oid(), to_id.into_datum()),
+                ],
+            );
+            debug2!("Pair reloaded: ({from_id},{to_id}), entries: {entry_count}.");
+        }
+    });
+
+    builder.sort_entries();
+    apply_cache_limits(&mut builder);
+    builder.link_graph();
+    if swap_cache(slot, key, current, builder.freeze()) {
+        return;
+    }
+
+    // released by an invalidation meanwhile
+    if CURRENCY_KEYS.share()[slot] == *key {
+        mark_pairs_stale(slot, pairs.iter().copied().collect());
+    }
+}
+
+/// Runs the load queries under a snapshot taken now, so a load started after the commit of a
+/// change reads it even when the statement snapshot predates the commit, see
+/// `kq_fx_install_triggers`. The queries of a transaction that has not written read the active
+/// snapshot, the others take their own. Parallel workers cannot take snapshots and keep the one
+/// of the leader. An error aborting the load pops the snapshot with the transaction.
+fn with_latest_snapshot<T>(load: impl FnOnce() -> T) -> T {
+    if unsafe { pg_sys::IsInParallelMode() } {
+        return load();
+    }
+    unsafe { pg_sys::PushActiveSnapshot(pg_sys::GetLatestSnapshot()) };
+    let result = load();
+    unsafe { pg_sys::PopActiveSnapshot() };
+    result
+}
+
+/// Runs the queries and freezes the result into `CURRENCY_AREA`.
+fn load_cache() -> SharedAllocation {
+    // Init Currencies (id and xuid)
+    let mut builder = CurrencyCacheBuilder::default();
+    let mut currencies_count: i64 = 0;
+    Spi::connect(|client| {
+        let select = client.select(&get_guc_string(&Q2_GET_CURRENCIES_XUID_INIT), None, None);
+        match select {
+            Ok(tuple_table) => {
+                for row in tuple_table {
+                    let id = row[1]
+                        .value::<i64>()
+                        .unwrap_or_else(|err| error!("server interface error - {err}"))
+                        .unwrap_or_else(|| error!("cannot get currency_id"));
+
+                    let xuid = row[2]
+                        .value::<String>()
+                        .unwrap_or_else(|err| error!("server interface error - {err}"))
+                        .unwrap_or_else(|| error!("cannot get currency_xuid"));
+
+                    let xuid_str =
+                        CurrencyXuid::from(heapless::String::from_str(xuid.as_str()).unwrap());
+
+                    builder.xuid_map.insert(xuid_str, id);
+
+                    if row.columns() >= 3 {
+                        let minor_units = row[3]
+                            .value::<i32>()
+                            .unwrap_or_else(|err| error!("server interface error - {err}"));
+                        if let Some(minor_units) = minor_units {
+                            builder.minor_units_map.insert(id, minor_units);
+                        }
+                    }
+
+                    currencies_count += 1;
+
+                    debug2!("Currency initialized. ID: {}, xuid: {}", id, xuid)
+                }
+            }
+            Err(spi_error) => {
+                error!("Cannot load currencies. {}", spi_error)
+            }
+        }
+    });
+
+    let entry_count = load_entries(
+        &mut builder,
+        &get_guc_string(&Q3_GET_CURRENCY_ENTRIES),
+        vec![(
+            PgBuiltInOids::INT4OID.oid(),
+            get_limit(&MAX_ENTRIES).into_datum(),
+        )],
+    );
+
+    load_shocks(&mut builder);
+
+    // Custom queries may return the rows in any order, the limits keep the most recent ones
+    builder.sort_entries();
+    apply_cache_limits(&mut builder);
+    builder.link_graph();
+
+    let allocation = builder.freeze();
+
+    debug2!("Cache ready, entries: {entry_count}.");
+    allocation
+}
+
+/// Runs the scenario factors query, see `kq.currency.q5_get_scenario_shocks`, adding the rows to
+/// the builder.
+fn load_shocks(builder: &mut CurrencyCacheBuilder) {
+    if Q5_GET_SCENARIO_SHOCKS
+        .get()
+        .is_none_or(|query| query.to_string_lossy().trim().is_empty())
+    {
+        return;
+    }
+    let query = get_guc_string(&Q5_GET_SCENARIO_SHOCKS);
+    Spi::connect(|client| {
+        let select = client.select(&query, None, None);
+        match select {
+            Ok(tuple_table) => {
+                let numeric_factors = tuple_table
+                    .column_type_oid(4)
+                    .unwrap_or_else(|err| error!("server interface error - {err}"))
+                    .value()
+                    == pg_sys::NUMERICOID;
+                for row in tuple_table {
+                    let scenario = row[1]
+                        .value::<String>()
+                        .unwrap_or_else(|err| error!("server interface error - {err}"))
+                        .unwrap_or_else(|| error!("cannot get scenario"));
+
+                    let from_id = row[2]
+                        .value::<i64>()
+                        .unwrap_or_else(|err| error!("server interface error - {err}"))
+                        .unwrap_or_else(|| error!("cannot get from_id"));
+
+                    let to_id = row[3]
+                        .value::<i64>()
+                        .unwrap_or_else(|err| error!("server interface error - {err}"))
+                        .unwrap_or_else(|| error!("cannot get to_id"));
+
+                    let (rate, exact_rate) = get_row_rate(&row, 4, numeric_factors);
+
+                    let scenario_id = builder.scenario_id(&scenario);
+                    builder.shocks.insert(
+                        (scenario_id, from_id, to_id),
+                        ShockFactor { rate, exact_rate },
+                    );
+
+                    debug2!("Scenario factor loaded: {scenario} ({from_id},{to_id}) => {rate}");
+                }
+            }
+            Err(spi_error) => {
+                error!("Cannot load scenario factors. {}", spi_error)
+            }
+        }
+    });
+}
+
+/// Reads a rate column, numeric rates are kept exactly, float rates by their shortest decimal
+/// form. The fixed-point rate is `None` when `kq.currency.exact_rates` is off or it does not fit.
+fn get_row_rate(row: &SpiHeapTupleData, column: usize, numeric: bool) -> (f64, Option<ExactRate>) {
+    let exact = EXACT_RATES.get();
+    if numeric {
+        let rate = row[column]
+            .value::<AnyNumeric>()
+            .unwrap_or_else(|err| error!("server interface error - {err}"))
+            .unwrap_or_else(|| error!("cannot get rate"));
+        let exact_rate = exact.then(|| parse_exact_rate(&rate.to_string())).flatten();
+        let rate = f64::try_from(rate)
+            .unwrap_or_else(|err| error!("cannot convert rate to float8 - {err}"));
+        (rate, exact_rate)
+    } else {
+        let rate = row[column]
+            .value::<f64>()
+            .unwrap_or_else(|err| error!("server interface error - {err}"))
+            .unwrap_or_else(|| error!("cannot get rate"));
+        (
+            rate,
+            exact.then(|| parse_exact_rate(&rate.to_string())).flatten(),
+        )
+    }
+}
+
+/// Reads an optional float8 or numeric column as float8.
+fn get_row_float(row: &SpiHeapTupleData, column: usize) -> Option<f64> {
+    if row[column].oid() == pg_sys::NUMERICOID {
+        row[column]
+            .value::<AnyNumeric>()
+            .unwrap_or_else(|err| error!("server interface error - {err}"))
+            .map(|value| {
+                f64::try_from(value)
+                    .unwrap_or_else(|err| error!("cannot convert rate to float8 - {err}"))
+            })
+    } else {
+        row[column]
+            .value::<f64>()
+            .unwrap_or_else(|err| error!("server interface error - {err}"))
+    }
+}
+
+/// Reads an optional text column, empty when the column is missing or NULL.
+fn get_row_text(row: &SpiHeapTupleData, column: Option<usize>) -> String {
+    column
+        .and_then(|column| {
+            row[column]
+                .value::<String>()
+                .unwrap_or_else(|err| error!("server interface error - {err}"))
+        })
+        .unwrap_or_default()
+}
+
+/// Reads a date, timestamp or timestamptz column as a point in time, timestamptz values are
+/// shifted to `time_zone` and dates are at midnight.
+fn get_row_instant(row: &SpiHeapTupleData, column: usize, time_zone: &str) -> StoreInstant {
+    let oid = row[column].oid();
+    if oid == pg_sys::TIMESTAMPTZOID {
+        let timestamp = row[column]
+            .value::<TimestampWithTimeZone>()
+            .unwrap_or_else(|err| error!("server interface error - {err}"))
+            .unwrap_or_else(|| error!("cannot get date"));
+        to_store_instant(timestamp, time_zone)
+            .unwrap_or_else(|| error!("cannot cache rates at infinite timestamps"))
+    } else if oid == pg_sys::TIMESTAMPOID {
+        let timestamp = row[column]
+            .value::<Timestamp>()
+            .unwrap_or_else(|err| error!("server interface error - {err}"))
+            .unwrap_or_else(|| error!("cannot get date"));
+        split_timestamp(timestamp)
+            .unwrap_or_else(|| error!("cannot cache rates at infinite timestamps"))
+    } else {
+        let date = row[column]
+            .value::<PgDate>()
+            .unwrap_or_else(|err| error!("server interface error - {err}"))
+            .unwrap_or_else(|| error!("cannot get date"));
+        (date.to_pg_epoch_days(), 0)
+    }
+}
+
+/// Point in time of a timestamp, `None` when infinite.
+fn split_timestamp(timestamp: Timestamp) -> Option<StoreInstant> {
+    if !timestamp.is_finite() {
+        return None;
+    }
+    let micros = timestamp.into_inner();
+    Some((
+        micros.div_euclid(USECS_PER_DAY) as StoreDate,
+        micros.rem_euclid(USECS_PER_DAY),
+    ))
+}
+
+/// Point in time of a timestamptz in `time_zone`, `None` when infinite.
+fn to_store_instant(timestamp: TimestampWithTimeZone, time_zone: &str) -> Option<StoreInstant> {
+    let timestamp = timestamp
+        .at_timezone(time_zone)
+        .unwrap_or_else(|err| error!("invalid kq.currency.time_zone {time_zone} - {err}"));
+    split_timestamp(timestamp)
+}
+
+/// Point in time of the date lookups.
+fn end_of_day(date: PgDate) -> StoreInstant {
+    (date.to_pg_epoch_days(), END_OF_DAY)
+}
+
+/// Formats a point in time for messages.
+fn format_instant((date, time): StoreInstant) -> String {
+    let date = unsafe { PgDate::from_pg_epoch_days(date) };
+    if time == 0 {
+        return date.to_string();
+    }
+    let seconds = time / 1_000_000;
+    format!(
+        "{date} {:02}:{:02}:{:02}.{:06}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        time % 1_000_000
+    )
+}
+
+/// Runs an entries query, see `kq.currency.q3_get_currency_entries`, adding the rows to the
+/// builder. Returns the number of rows.
+fn load_entries(
+    builder: &mut CurrencyCacheBuilder,
+    query: &str,
+    args: Vec<(PgOid, Option<pg_sys::Datum>)>,
+) -> i64 {
+    let mut entry_count: i64 = 0;
+    Spi::connect(|client| {
+        let select = client.select(query, None, Some(args));
+        match select {
+            Ok(tuple_table) => {
+                let numeric_rates = tuple_table
+                    .column_type_oid(4)
+                    .unwrap_or_else(|err| error!("server interface error - {err}"))
+                    .value()
+                    == pg_sys::NUMERICOID;
+                let time_zone = get_time_zone();
+                // Optional columns are looked up by name, in any order after the first four.
+                let column = |name| tuple_table.column_ordinal(name).ok();
+                let rate_type_column = column("rate_type");
+                let scenario_column = column("scenario");
+                let bid_column = column("bid");
+                let ask_column = column("ask");
+                let effective_to_column = column("effective_to");
+
+                for row in tuple_table {
+                    let from_id = row[1]
+                        .value::<i64>()
+                        .unwrap_or_else(|err| error!("server interface error - {err}"))
+                        .unwrap_or_else(|| error!("cannot get from_id"));
+
+                    let to_id = row[2]
+                        .value::<i64>()
+                        .unwrap_or_else(|err| error!("server interface error - {err}"))
+                        .unwrap_or_else(|| error!("cannot get to_id"));
+
+                    let (date, time) = get_row_instant(&row, 3, time_zone);
+
+                    let (rate, exact_rate) = get_row_rate(&row, 4, numeric_rates);
+
+                    // Quotes are optional, missing or NULL sides are the mid rate.
+                    let bid = bid_column.and_then(|column| get_row_float(&row, column));
+                    let ask = ask_column.and_then(|column| get_row_float(&row, column));
+
+                    // Rows without an end of validity are valid until the next one.
+                    let effective_to = effective_to_column.and_then(|column| {
+                        row[column]
+                            .value::<PgDate>()
+                            .unwrap_or_else(|err| error!("server interface error - {err}"))
+                    });
+
+                    let entry = StoreRateEntry {
+                        date,
+                        time,
+                        rate,
+                        exact_rate,
+                        bid: bid.unwrap_or(rate),
+                        ask: ask.unwrap_or(rate),
+                        effective_to: effective_to
+                            .map(|date| date.to_pg_epoch_days())
+                            .unwrap_or(OPEN_ENDED),
+                    };
+
+                    // Rows without a rate type column or with NULL are the default rate type.
+                    let rate_type = get_row_text(&row, rate_type_column);
+                    let rate_type = builder.rate_type_id(&rate_type);
+
+                    // Rows without a scenario column or with NULL are the base rates.
+                    let scenario = get_row_text(&row, scenario_column);
+                    let scenario = builder.scenario_id(&scenario);
+
+                    builder
+                        .data_map
+                        .entry((from_id, to_id, rate_type, scenario))
+                        .or_default()
+                        .push(entry);
+
+                    entry_count += 1;
+
+                    debug2!(
+                        "Inserted into shared cache: ({},{}) => ({}, {})",
+                        from_id,
+                        to_id,
+                        format_instant((date, time)),
+                        rate
+                    );
+                }
+            }
+            Err(spi_error) => {
+                error!("Cannot load currency rates. {}", spi_error)
+            }
+        }
+    });
+
+    entry_count
+}
+
+/// Applies the `kq.currency.max_*` limits to a loaded cache, see `OverflowPolicy`.
+fn apply_cache_limits(builder: &mut CurrencyCacheBuilder) {
+    let max_currencies = get_limit(&MAX_CURRENCIES) as usize;
+    let currencies = builder.xuid_map.len();
+    if currencies > max_currencies {
+        check_overflow(
+            "kq.currency.max_currencies",
+            format!("{currencies} currencies required, the limit is {max_currencies}."),
+        );
+        let mut ids: Vec<i64> = builder.xuid_map.values().copied().collect();
+        ids.sort_unstable();
+        let max_id = ids[max_currencies - 1];
+        builder.xuid_map.retain(|_, id| *id <= max_id);
+        builder.minor_units_map.retain(|id, _| *id <= max_id);
+        let pairs = builder.data_map.len();
+        let mut rows = 0;
+        builder
+            .data_map
+            .retain(|&(from_id, to_id, _, _), data_vec| {
+                let kept = from_id <= max_id && to_id <= max_id;
+                if !kept {
+                    rows += data_vec.len();
+                }
+                kept
+            });
+        builder
+            .shocks
+            .retain(|&(_, from_id, to_id), _| from_id <= max_id && to_id <= max_id);
+        warning!(
+            "cache load exceeds kq.currency.max_currencies, {} currencies, {} pairs and {rows} rows dropped",
+            currencies - builder.xuid_map.len(),
+            pairs - builder.data_map.len()
+        );
+    }
+
+    let max_id_pairs = get_limit(&MAX_ID_PAIRS) as usize;
+    let pairs = builder.data_map.len();
+    if pairs > max_id_pairs {
+        check_overflow(
+            "kq.currency.max_pairs",
+            format!("{pairs} currency pairs required, the limit is {max_id_pairs}."),
+        );
+        let first_dropped = *builder.data_map.keys().nth(max_id_pairs).unwrap();
+        let dropped = builder.data_map.split_off(&first_dropped);
+        warning!(
+            "cache load exceeds kq.currency.max_pairs, {} pairs and {} rows dropped",
+            dropped.len(),
+            dropped.values().map(Vec::len).sum::<usize>()
+        );
+    }
+
+    let max_entries = get_limit(&MAX_ENTRIES) as usize;
+    let largest = builder
+        .data_map
+        .iter()
+        .max_by_key(|(_, data_vec)| data_vec.len());
+    if let Some((&(from_id, to_id, _, _), data_vec)) = largest {
+        if data_vec.len() > max_entries {
+            check_overflow(
+                "kq.currency.max_entries",
+                format!(
+                    "{} entries required for ({from_id},{to_id}), the limit is {max_entries}.",
+                    data_vec.len()
+                ),
+            );
+            let mut dropped = 0;
+            for data_vec in builder.data_map.values_mut() {
+                if data_vec.len() > max_entries {
+                    dropped += data_vec.len() - max_entries;
+                    data_vec.drain(..data_vec.len() - max_entries);
+                }
+            }
+            warning!("cache load exceeds kq.currency.max_entries, {dropped} rows dropped");
+        }
+    }
+}
+
+/// Fails the cache load when `kq.currency.overflow` is `error`.
+fn check_overflow(limit: &str, detail: String) {
+    if OVERFLOW.get() == OverflowPolicy::Error {
+        ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_PROGRAM_LIMIT_EXCEEDED,
+            format!("cache load exceeds {limit}"),
+            detail
+        );
+    }
+}
+
+fn add_graph_edge(graph: &mut BTreeMap<i64, Vec<i64>>, from_id: i64, to_id: i64) {
+    let adjacent = graph.entry(from_id).or_default();
+    if !adjacent.contains(&to_id) {
+        adjacent.push(to_id);
+    }
+}
+
+/// Parses a decimal string into an `ExactRate`, rounding half away from zero past
+/// `EXACT_RATE_SCALE` decimal places. Returns `None` for non-decimal values or on overflow.
+fn parse_exact_rate(value: &str) -> Option<ExactRate> {
+    let (negative, digits) = match value.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, value),
+    };
+    let (int_part, frac_part) = digits.split_once('.').unwrap_or((digits, ""));
+    if (int_part.is_empty() && frac_part.is_empty())
+        || !int_part.bytes().all(|digit| digit.is_ascii_digit())
+        || !frac_part.bytes().all(|digit| digit.is_ascii_digit())
+    {
+        return None;
+    }
+
+    let scaled_digits = int_part.bytes().chain(
+        frac_part
+            .bytes()
+            .chain(std::iter::repeat(b'0'))
+            .take(EXACT_RATE_SCALE as usize),
+    );
+    let mut exact_rate: ExactRate = 0;
+    for digit in scaled_digits {
+        exact_rate = exact_rate
+            .checked_mul(10)?
+            .checked_add((digit - b'0') as ExactRate)?;
+    }
+    if frac_part.as_bytes().get(EXACT_RATE_SCALE as usize) >= Some(&b'5') {
+        exact_rate = exact_rate.checked_add(1)?;
+    }
+
+    Some(if negative { -exact_rate } else { exact_rate })
+}
+
+fn exact_rate_to_numeric(exact_rate: ExactRate) -> AnyNumeric {
+    let scale = (10 as ExactRate).pow(EXACT_RATE_SCALE);
+    let value = format!(
+        "{}{}.{:0width$}",
+        if exact_rate < 0 { "-" } else { "" },
+        (exact_rate / scale).unsigned_abs(),
+        (exact_rate % scale).unsigned_abs(),
+        width = EXACT_RATE_SCALE as usize
+    );
+    let value = value.trim_end_matches('0').trim_end_matches('.');
+    AnyNumeric::try_from(value)
+        .unwrap_or_else(|err| error!("cannot convert rate {value} to numeric - {err}"))
+}
+
+/// Numeric of a float8 rate, by its shortest decimal form.
+fn float_rate_to_numeric(rate: f64) -> AnyNumeric {
+    AnyNumeric::try_from(rate.to_string().as_str())
+        .unwrap_or_else(|err| error!("cannot convert rate {rate} to numeric - {err}"))
+}
+
+/// Query of a GUC, `{rate_set}` is replaced by the quoted schema of the current rate set so the
+/// queries read its tables without depending on the search path.
+fn get_guc_string(guc: &GucSetting<Option<&'static CStr>>) -> String {
+    let value = String::from_utf8_lossy(guc.get().expect("Cannot get GUC value.").to_bytes())
+        .to_string()
+        .replace('\n', " ")
+        .replace("{rate_set}", &quote_ident(get_rate_set()));
+    debug2!("Query: {value}");
+    value
+}
+
+/// This method prevents using the extension in incompatible databases.
+fn validate_compatible_db() -> Result<(), String> {
+    let spi_result: SpiResult<Option<bool>> = Spi::get_one_with_args(
+        &get_guc_string(&Q1_VALIDATION_QUERY),
+        vec![(PgBuiltInOids::TEXTOID.oid(), get_rate_set().into_datum())],
+    );
+    match spi_result {
+        Ok(found_tables_opt) => match found_tables_opt {
+            None => Err(
+                "The current database is not compatible with the ketteQ FX extension.".to_string(),
+            ),
+            Some(valid) => {
+                if !valid {
+                    Err(
+                        "The current database is not compatible with the ketteQ FX extension."
+                            .to_string(),
+                    )
+                } else {
+                    Ok(())
+                }
+            }
+        },
+        Err(spi_error) => Err(format!("Cannot validate current database. {}", spi_error)),
+    }
+}
+
+// Exported Functions
+
+#[pg_extern]
+fn kq_fx_check_db() -> String {
+    match validate_compatible_db() {
+        Ok(_) => "Database is compatible with the extension.".to_string(),
+        Err(error_msg) => error_msg,
+    }
+}
+
+#[pg_extern]
+fn kq_fx_invalidate_cache() -> &'static str {
+    // nothing is cached when the rate set has no slot
+    let Some(slot) = find_cache_slot(get_rate_set()) else {
+        return "Cache invalidated.";
+    };
+    debug2!("Waiting for lock...");
+    let mut caches = CURRENCY_CACHE.exclusive();
+
+    if let Some(allocation) = caches[slot].allocation.take() {
+        CURRENCY_AREA.free(allocation);
+    }
+
+    let control = &mut CURRENCY_CONTROL.exclusive()[slot];
+    if control.cache_being_filled {
+        // the load in progress keeps the slot and is reloaded by the next lookup
+        control.cache_filled = false;
+        control.cache_stale = true;
+    } else {
+        *control = CurrencyControl::default();
+        CURRENCY_KEYS.exclusive()[slot] = CacheKey::default();
+    }
+
+    "Cache invalidated."
+}
+
+/// Reloads the cache while lookups keep reading the current one, which is replaced at once when
+/// the new one is ready. Populates the cache when it is not loaded.
+#[pg_extern]
+fn kq_fx_refresh_cache() -> &'static str {
+    let key = cache_key();
+    let Some(slot) = find_cache_slot(get_rate_set()).filter(|&slot| is_cache_filled(slot)) else {
+        ensure_cache_populated();
+        return "Cache populated.";
+    };
+
+    if let Err(msg) = validate_compatible_db() {
+        error!("{}", msg);
+    }
+
+    // an invalidation or another load meanwhile keeps the cache it left
+    let current = CURRENCY_CACHE.share()[slot].allocation;
+    if !swap_cache(slot, &key, current, with_latest_snapshot(load_cache)) {
+        ensure_cache_populated();
+        return "Cache populated.";
+    }
+    "Cache refreshed."
+}
+
+/// Statement trigger marking the cache stale when the transaction commits, see
+/// `kq_fx_install_triggers`.
+#[pg_trigger]
+fn kq_fx_invalidate_cache_trigger<'a>(
+    trigger: &'a PgTrigger<'a>,
+) -> Result<Option<PgHeapTuple<'a, AllocatedByPostgres>>, PgHeapTupleError> {
+    thread_local! {
+        // Rate sets changed by the current transaction
+        static CHANGED_RATE_SETS: RefCell<BTreeSet<String>> = const { RefCell::new(BTreeSet::new()) };
+    }
+
+    let rate_set = trigger
+        .table_schema()
+        .unwrap_or_else(|err| error!("cannot get trigger table schema - {err}"));
+    let first_change = CHANGED_RATE_SETS.with_borrow_mut(|rate_sets| {
+        let first_change = rate_sets.is_empty();
+        rate_sets.insert(rate_set);
+        first_change
+    });
+    if first_change {
+        register_xact_callback(PgXactCallbackEvent::Commit, || {
+            for rate_set in CHANGED_RATE_SETS.take() {
+                // nothing is cached when the rate set has no slot
+                if let Some(slot) = find_cache_slot(&rate_set) {
+                    mark_cache_stale(slot);
+                }
+            }
+        });
+        register_xact_callback(PgXactCallbackEvent::Abort, || {
+            CHANGED_RATE_SETS.take();
+        });
+    }
+    Ok(None)
+}
+
+/// Creates the `kq_fx_invalidate_cache` trigger on each table, replacing it if it exists.
+#[pg_extern]
+fn kq_fx_install_triggers(
+    tables: default!(Vec<String>, "ARRAY['plan.currency', 'plan.fx_rate']"),
+) -> &'static str {
+    for table in tables {
+        let table = get_table_name(&table);
+        Spi::run(&format!(
+            "CREATE OR REPLACE TRIGGER kq_fx_invalidate_cache \
+             AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON {table} \
+             FOR EACH STATEMENT EXECUTE FUNCTION kq_fx_invalidate_cache_trigger()"
+        ))
+        .unwrap_or_else(|err| error!("cannot create trigger on {table} - {err}"));
+    }
+    "Triggers installed."
+}
+
+/// Drops the `kq_fx_invalidate_cache` and `kq_fx_refresh_pair` triggers from each table.
+#[pg_extern]
+fn kq_fx_uninstall_triggers(
+    tables: default!(Vec<String>, "ARRAY['plan.currency', 'plan.fx_rate']"),
+) -> &'static str {
+    for table in tables {
+        let table = get_table_name(&table);
+        for trigger in ["kq_fx_invalidate_cache", "kq_fx_refresh_pair"] {
+            Spi::run(&format!("DROP TRIGGER IF EXISTS {trigger} ON {table}"))
+                .unwrap_or_else(|err| error!("cannot drop trigger on {table} - {err}"));
+        }
+    }
+    "Triggers uninstalled."
+}
+
+/// SQL identifier of a value, quoted when needed.
+fn quote_ident(value: &str) -> String {
+    if !value.is_empty()
+        && !value.starts_with(|c: char| c.is_ascii_digit())
+        && value
+            .bytes()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == b'_')
+    {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    }
+}
+
+/// SQL string literal of a value.
+fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Quoted name of an existing table, errors when it does not exist.
+fn get_table_name(table: &str) -> String {
+    Spi::get_one_with_args::<String>(
+        "SELECT $1::regclass::text",
+        vec![(PgBuiltInOids::TEXTOID.oid(), table.into_datum())],
+    )
+    .unwrap_or_else(|err| error!("cannot find table {table} - {err}"))
+    .unwrap_or_else(|| error!("cannot find table {table}"))
+}
+
+/// Re-queries a single pair, lookups keep reading the current cache while it is copied. Populates
+/// the cache when it is not loaded.
+#[pg_extern]
+fn kq_fx_refresh_pair(currency_id: i64, to_currency_id: i64) -> &'static str {
+    let key = cache_key();
+    let Some(slot) = find_cache_slot(get_rate_set()).filter(|&slot| is_cache_filled(slot)) else {
+        ensure_cache_populated();
+        return "Cache populated.";
+    };
+
+    if let Err(msg) = validate_compatible_db() {
+        error!("{}", msg);
+    }
+
+    reload_pairs(slot, &key, &[(currency_id, to_currency_id)]);
+    "Pair refreshed."
+}
+
+/// Row trigger marking the changed pairs stale when the transaction commits, see
+/// `kq_fx_install_pair_triggers`. Takes the from and to currency id columns as arguments.
+#[pg_trigger]
+fn kq_fx_refresh_pair_trigger<'a>(
+    trigger: &'a PgTrigger<'a>,
+) -> Result<Option<PgHeapTuple<'a, AllocatedByPostgres>>, PgHeapTupleError> {
+    thread_local! {
+        // Pairs changed by the current transaction, by rate set
+        static CHANGED_PAIRS: RefCell<BTreeMap<String, BTreeSet<FromToIdPair>>> = const { RefCell::new(BTreeMap::new()) };
+    }
+
+    let rate_set = trigger
+        .table_schema()
+        .unwrap_or_else(|err| error!("cannot get trigger table schema - {err}"));
+
+    let args = trigger
+        .extra_args()
+        .unwrap_or_else(|err| error!("cannot get trigger arguments - {err}"));
+    let from_column = args.first().map_or("currency_id", String::as_str);
+    let to_column = args.get(1).map_or("to_currency_id", String::as_str);
+
+    for tuple in [trigger.old(), trigger.new()].into_iter().flatten() {
+        let get_id = |column: &str| {
+            tuple
+                .get_by_name::<i64>(column)
+                .unwrap_or_else(|err| error!("cannot get {column} - {err}"))
+                .unwrap_or_else(|| error!("cannot get {column}"))
+        };
+        let pair = (get_id(from_column), get_id(to_column));
+
+        let first_change = CHANGED_PAIRS.with_borrow_mut(|pairs| {
+            let first_change = pairs.is_empty();
+            pairs.entry(rate_set.clone()).or_default().insert(pair);
+            first_change
+        });
+        if first_change {
+            register_xact_callback(PgXactCallbackEvent::Commit, || {
+                for (rate_set, pairs) in CHANGED_PAIRS.take() {
+                    if let Some(slot) = find_cache_slot(&rate_set) {
+                        mark_pairs_stale(slot, pairs);
+                    }
+                }
+            });
+            register_xact_callback(PgXactCallbackEvent::Abort, || {
+                CHANGED_PAIRS.take();
+            });
+        }
+    }
+    Ok(None)
+}
+
+/// Creates the `kq_fx_refresh_pair` row trigger on a rates table, and the `kq_fx_invalidate_cache`
+/// trigger for `TRUNCATE`, replacing them if they exist.
+#[pg_extern]
+fn kq_fx_install_pair_triggers(
+    table: default!(&str, "'plan.fx_rate'"),
+    currency_column: default!(&str, "'currency_id'"),
+    to_currency_column: default!(&str, "'to_currency_id'"),
+) -> &'static str {
+    let table = get_table_name(table);
+    Spi::run(&format!(
+        "CREATE OR REPLACE TRIGGER kq_fx_refresh_pair \
+         AFTER INSERT OR UPDATE OR DELETE ON {table} \
+         FOR EACH ROW EXECUTE FUNCTION kq_fx_refresh_pair_trigger({}, {})",
+        quote_literal(currency_column),
+        quote_literal(to_currency_column)
+    ))
+    .unwrap_or_else(|err| error!("cannot create trigger on {table} - {err}"));
+    Spi::run(&format!(
+        "CREATE OR REPLACE TRIGGER kq_fx_invalidate_cache \
+         AFTER TRUNCATE ON {table} \
+         FOR EACH STATEMENT EXECUTE FUNCTION kq_fx_invalidate_cache_trigger()"
+    ))
+    .unwrap_or_else(|err| error!("cannot create trigger on {table} - {err}"));
+    "Triggers installed."
+}
+
+#[pg_extern(parallel_safe)]
+fn kq_fx_populate_cache() -> &'static str {
+    ensure_cache_populated();
+    "Cache populated."
+}
+
+#[pg_extern(parallel_safe)]
+#[allow(clippy::type_complexity)]
+fn kq_fx_display_cache() -> TableIterator<
+    'static,
+    (
+        name!(currency_id, i64),
+        name!(to_currency_id, i64),
+        name!(date, PgDate),
+        name!(rate, f64),
+        name!(rate_type, Option<String>),
+        name!(scenario, Option<String>),
+    ),
+> {
+    let slot = ensure_cache_populated();
+    let caches = CURRENCY_CACHE.share();
+    let maps = slot_maps(&caches, slot, None, None);
+    let rate_type_names: HashMap<RateTypeId, String> = maps
+        .rate_types
+        .iter()
+        .map(|(name, id)| (*id, name.to_string()))
+        .collect();
+    let scenario_names: HashMap<ScenarioId, String> = maps
+        .scenarios
+        .iter()
+        .map(|(name, id)| (*id, name.to_string()))
+        .collect();
+    let result_vec: Vec<(_, _, _, _, _, _)> = maps
+        .data_map
+        .entries
+        .iter()
+        .flat_map(|((from_id, to_id, rate_type, scenario), data_vec)| {
+            let rate_type = rate_type_names.get(rate_type).cloned();
+            let scenario = scenario_names.get(scenario).cloned();
+            data_vec.iter().map(move |entry| unsafe {
+                let date = pgrx::datum::Date::from_pg_epoch_days(entry.date);
+                (
+                    *from_id,
+                    *to_id,
+                    date,
+                    entry.rate,
+                    rate_type.clone(),
+                    scenario.clone(),
+                )
+            })
+        })
+        .collect();
+    TableIterator::new(result_vec)
+}
+
+/// Returns the entry in effect at `at` from an ASC ordered entries vector. Points in time outside
+/// the cached range follow `kq.currency.out_of_range`, the rates of the last date are in effect
+/// until the end of the day.
+fn find_entry(entries: &[StoreRateEntry], at: StoreInstant) -> Option<&StoreRateEntry> {
+    let index = entries.partition_point(|entry| entry.instant() <= at);
+    entry_in_effect(entries, index, at)
+}
+
+/// Returns the entry in effect at `at`, see `find_entry`. `index` is the number of entries at or
+/// before `at`.
+fn entry_in_effect(
+    entries: &[StoreRateEntry],
+    index: usize,
+    at: StoreInstant,
+) -> Option<&StoreRateEntry> {
+    let first = entries.first()?;
+    if index == 0 {
+        return match OUT_OF_RANGE.get() {
+            OutOfRangePolicy::Clamp => Some(first),
+            _ => None,
+        };
+    }
+    let last = entries.last()?;
+    if at.0 > last.date {
+        return match OUT_OF_RANGE.get() {
+            OutOfRangePolicy::Carry | OutOfRangePolicy::Clamp => carry_entry(last, at),
+            OutOfRangePolicy::Null | OutOfRangePolicy::Error => None,
+        };
+    }
+    let entry = &entries[index - 1];
+    if entry.instant() == at {
+        Some(entry)
+    } else {
+        carry_entry(entry, at)
+    }
+}
+
+/// Returns an entry for a later `at`, unless it is past its `effective_to` or older than
+/// `kq.currency.max_staleness_days`.
+fn carry_entry(entry: &StoreRateEntry, at: StoreInstant) -> Option<&StoreRateEntry> {
+    if at.0 > entry.effective_to {
+        return None;
+    }
+    let max_staleness_days = MAX_STALENESS_DAYS.get();
+    // infinite dates are the i32 bounds
+    if max_staleness_days > 0 && at.0 as i64 - entry.date as i64 > max_staleness_days as i64 {
+        return None;
+    }
+    Some(entry)
+}
+
+/// Returns the rate in effect at `at`, see `find_entry`.
+fn find_rate(entries: &[StoreRateEntry], at: StoreInstant) -> Option<f64> {
+    find_entry(entries, at).map(|entry| entry.rate)
+}
+
+/// Returns the quote in effect at `at`, see `find_entry`.
+fn find_quote(entries: &[StoreRateEntry], at: StoreInstant) -> Option<Quote> {
+    find_entry(entries, at).map(|entry| Quote {
+        bid: entry.bid,
+        mid: entry.rate,
+        ask: entry.ask,
+    })
+}
+
+/// Returns the fixed-point rate in effect at `at` as numeric, see `find_entry`.
+fn find_exact_rate(entries: &[StoreRateEntry], at: StoreInstant) -> Option<AnyNumeric> {
+    find_entry(entries, at).map(|entry| match entry.exact_rate {
+        Some(exact_rate) => exact_rate_to_numeric(exact_rate),
+        None => float_rate_to_numeric(entry.rate),
+    })
+}
+
+/// Raises an error for a missing rate when `kq.currency.out_of_range` is `error`.
+fn check_rate_found<T>(
+    rate: Option<T>,
+    currency_id: i64,
+    to_currency_id: i64,
+    at: impl std::fmt::Display,
+) -> Option<T> {
+    if rate.is_none() && OUT_OF_RANGE.get() == OutOfRangePolicy::Error {
+        error!("No rate available for ({currency_id},{to_currency_id}) at {at}");
+    }
+    rate
+}
+
+/// Linearly interpolates the rate at `at` between the surrounding entries of an ASC ordered
+/// entries vector. Outside the cached range the first two or last two entries are
+/// extrapolated when `kq.currency.extrapolate` is enabled, otherwise `find_rate` applies.
+fn interpolate_rate(entries: &[StoreRateEntry], at: StoreInstant) -> Option<f64> {
+    let extrapolate = EXTRAPOLATE.get() && entries.len() > 1;
+    let index = match entries.binary_search_by(|entry| entry.instant().cmp(&at)) {
+        Ok(index) => return Some(entries[index].rate),
+        Err(index) => index,
+    };
+    // No rate in effect past the validity of the previous entry
+    if index > 0 && at.0 > entries[index - 1].effective_to {
+        return None;
+    }
+    let (lower, upper) = if index == 0 {
+        if !extrapolate {
+            return find_rate(entries, at);
+        }
+        (entries[0], entries[1])
+    } else if index == entries.len() {
+        if !extrapolate {
+            return find_rate(entries, at);
+        }
+        (entries[index - 2], entries[index - 1])
+    } else {
+        (entries[index - 1], entries[index])
+    };
+    if lower.instant() == upper.instant() {
+        return Some(upper.rate);
+    }
+    // Microseconds of the far dates do not fit in i64
+    let micros = |(date, time): StoreInstant| date as i128 * USECS_PER_DAY as i128 + time as i128;
+    let ratio = (micros(at) - micros(lower.instant())) as f64
+        / (micros(upper.instant()) - micros(lower.instant())) as f64;
+    Some(lower.rate + (upper.rate - lower.rate) * ratio)
+}
+
+/// Resolves the pivot currency id from the `kq.currency.pivot_currency` GUC, `None` when it is not
+/// set or not a currency of the rate set, so no pair is triangulated.
+fn get_pivot_currency_id(xuid_map: &CurrencyXuidMap) -> Option<i64> {
+    let pivot_xuid = PIVOT_CURRENCY_XUID.get()?.to_str().ok()?.trim();
+    if pivot_xuid.is_empty() {
+        return None;
+    }
+    let pivot_xuid = CurrencyXuid::from_str(pivot_xuid).ok()?;
+    xuid_map.get(&pivot_xuid).copied()
+}
+
+/// Looks up a single cached pair, falling back to the inverse of the opposite pair when
+/// `kq.currency.inverse_fallback` is enabled. Returns `None` when neither pair is cached.
+fn get_leg_rate<T: ComposableRate>(
+    data_map: &CurrencyDataMap,
+    currency_id: i64,
+    to_currency_id: i64,
+    at: StoreInstant,
+    find: RateFinder<T>,
+) -> Option<(Option<T>, RatePath)> {
+    if let Some(rate) = data_map.find((currency_id, to_currency_id), at, find) {
+        return Some((rate, RatePath::Direct));
+    }
+
+    if INVERSE_FALLBACK.get() {
+        if let Some(rate) = data_map.find((to_currency_id, currency_id), at, find) {
+            let rate = rate.and_then(|rate| rate.inverse());
+            return Some((rate, RatePath::Inverse));
+        }
+    }
+
+    None
+}
+
+/// Looks up the rate for a pair, triangulating through the pivot currency when the pair is not
+/// cached. `slot` is the cache from `ensure_cache_populated`.
+fn get_rate<T: ComposableRate>(
+    slot: usize,
+    currency_id: i64,
+    to_currency_id: i64,
+    at: StoreInstant,
+    find: RateFinder<T>,
+    rate_type: Option<&str>,
+    scenario: Option<&str>,
+) -> (Option<T>, RatePath) {
+    if currency_id == to_currency_id {
+        return (Some(T::one()), RatePath::Identity);
+    }
+
+    let caches = CURRENCY_CACHE.share();
+    let maps = slot_maps(&caches, slot, rate_type, scenario);
+    let data_map = &maps.data_map;
+    if let Some((rate, path)) = get_leg_rate(data_map, currency_id, to_currency_id, at, find) {
+        return match rate {
+            Some(rate) => (Some(rate), path),
+            None => (None, RatePath::NotFound),
+        };
+    }
+
+    if let Some(pivot_id) = get_pivot_currency_id(&maps.xuid_map) {
+        if pivot_id != currency_id && pivot_id != to_currency_id {
+            let from_leg =
+                get_leg_rate(data_map, currency_id, pivot_id, at, find).and_then(|(rate, _)| rate);
+            let to_leg = get_leg_rate(data_map, pivot_id, to_currency_id, at, find)
+                .and_then(|(rate, _)| rate);
+            if let (Some(from_rate), Some(to_rate)) = (from_leg, to_leg) {
+                return (Some(from_rate.compose(to_rate)), RatePath::Triangulated);
+            }
+        }
+    }
+
+    (None, RatePath::NotFound)
+}
+
+#[pg_extern(parallel_safe, stable)]
+fn kq_fx_get_rate(currency_id: i64, to_currency_id: i64, date: PgDate) -> Option<f64> {
+    if currency_id == to_currency_id {
+        return Some(1.0);
+    }
+
+    let slot = ensure_cache_populated();
+
+    let rate = get_rate(
+        slot,
+        currency_id,
+        to_currency_id,
+        end_of_day(date),
+        find_rate,
+        None,
+        None,
+    )
+    .0;
+    check_rate_found(rate, currency_id, to_currency_id, date)
+}
+
+/// Rate in effect at a point in time, shifted to `kq.currency.time_zone`.
+#[pg_extern(parallel_safe, stable)]
+fn kq_fx_get_rate_at(
+    currency_id: i64,
+    to_currency_id: i64,
+    timestamp: TimestampWithTimeZone,
+) -> Option<f64> {
+    get_rate_at(
+        currency_id,
+        to_currency_id,
+        to_store_instant(timestamp, get_time_zone()),
+        timestamp,
+    )
+}
+
+/// Rate in effect at a point in time of `kq.currency.time_zone`.
+#[pg_extern(parallel_safe, stable)]
+fn kq_fx_get_rate_at_local(
+    currency_id: i64,
+    to_currency_id: i64,
+    timestamp: Timestamp,
+) -> Option<f64> {
+    get_rate_at(
+        currency_id,
+        to_currency_id,
+        split_timestamp(timestamp),
+        timestamp,
+    )
+}
+
+/// Looks up the rate at a point in time, `None` for infinite timestamps.
+fn get_rate_at(
+    currency_id: i64,
+    to_currency_id: i64,
+    at: Option<StoreInstant>,
+    timestamp: impl std::fmt::Display,
+) -> Option<f64> {
+    if currency_id == to_currency_id {
+        return Some(1.0);
+    }
+
+    let slot = ensure_cache_populated();
+
+    let rate =
+        at.and_then(|at| get_rate(slot, currency_id, to_currency_id, at, find_rate, None, None).0);
+    check_rate_found(rate, currency_id, to_currency_id, timestamp)
+}
+
+/// Rate of the given rate type, regardless of `kq.currency.rate_type`.
+#[pg_extern(parallel_safe, stable, name = "kq_fx_get_rate")]
+fn kq_fx_get_rate_by_type(
+    currency_id: i64,
+    to_currency_id: i64,
+    date: PgDate,
+    rate_type: &str,
+) -> Option<f64> {
+    if currency_id == to_currency_id {
+        return Some(1.0);
+    }
+
+    let slot = ensure_cache_populated();
+
+    let rate = get_rate(
+        slot,
+        currency_id,
+        to_currency_id,
+        end_of_day(date),
+        find_rate,
+        Some(rate_type),
+        None,
+    )
+    .0;
+    check_rate_found(rate, currency_id, to_currency_id, date)
+}
+
+/// Rate in a scenario, regardless of `kq.currency.scenario`. `rate_type` defaults to
+/// `kq.currency.rate_type`.
+#[pg_extern(parallel_safe, stable)]
+fn kq_fx_get_rate_scenario(
+    currency_id: i64,
+    to_currency_id: i64,
+    date: PgDate,
+    scenario: &str,
+    rate_type: default!(Option<&str>, "NULL"),
+) -> Option<f64> {
+    if currency_id == to_currency_id {
+        return Some(1.0);
+    }
+
+    let slot = ensure_cache_populated();
+
+    let rate = get_rate(
+        slot,
+        currency_id,
+        to_currency_id,
+        end_of_day(date),
+        find_rate,
+        rate_type,
+        Some(scenario),
+    )
+    .0;
+    check_rate_found(rate, currency_id, to_currency_id, date)
+}
+
+#[pg_extern(parallel_safe, stable)]
+fn kq_fx_get_rate_numeric(
+    currency_id: i64,
+    to_currency_id: i64,
+    date: PgDate,
+) -> Option<AnyNumeric> {
+    if currency_id == to_currency_id {
+        return Some(AnyNumeric::one());
+    }
+
+    let slot = ensure_cache_populated();
+
+    let rate = get_rate(
+        slot,
+        currency_id,
+        to_currency_id,
+        end_of_day(date),
+        find_exact_rate,
+        None,
+        None,
+    )
+    .0;
+    check_rate_found(rate, currency_id, to_currency_id, date)
+}
+
+#[pg_extern(parallel_safe, stable)]
+fn kq_fx_get_rate_interpolated(currency_id: i64, to_currency_id: i64, date: PgDate) -> Option<f64> {
+    if !date.is_finite() {
+        error!("interpolation requires a finite date: {date}");
+    }
+    if currency_id == to_currency_id {
+        return Some(1.0);
+    }
+
+    let slot = ensure_cache_populated();
+
+    let rate = get_rate(
+        slot,
+        currency_id,
+        to_currency_id,
+        (date.to_pg_epoch_days(), 0),
+        interpolate_rate,
+        None,
+        None,
+    )
+    .0;
+    check_rate_found(rate, currency_id, to_currency_id, date)
+}
+
+/// Reports how `kq_fx_get_rate` resolves the rate for the given arguments.
+#[pg_extern(parallel_safe, stable)]
+fn kq_fx_get_rate_source(currency_id: i64, to_currency_id: i64, date: PgDate) -> &'static str {
+    if currency_id == to_currency_id {
+        return RatePath::Identity.as_str();
+    }
+
+    let slot = ensure_cache_populated();
+
+    get_rate(
+        slot,
+        currency_id,
+        to_currency_id,
+        end_of_day(date),
+        find_rate,
+        None,
+        None,
+    )
+    .1
+    .as_str()
+}
+
+/// Finds the chain with the fewest conversions from `currency_id` to `to_currency_id`, walking
+/// only pairs with a rate at `at`. Returns the composed rate and the visited currency ids.
+/// `slot` is the cache from `ensure_cache_populated`.
+fn get_rate_path(
+    slot: usize,
+    currency_id: i64,
+    to_currency_id: i64,
+    at: StoreInstant,
+) -> Option<(f64, Vec<i64>)> {
+    if currency_id == to_currency_id {
+        return Some((1.0, vec![currency_id]));
+    }
+
+    let max_hops = MAX_HOPS.get().max(1) as usize;
+    let caches = CURRENCY_CACHE.share();
+    let maps = slot_maps(&caches, slot, None, None);
+    let data_map = maps.data_map;
+    let graph = maps.graph;
+
+    // CURRENCY_ID => (PREVIOUS CURRENCY_ID, RATE FROM THE PREVIOUS ONE, HOPS)
+    let mut visited: HashMap<i64, (i64, f64, usize)> = HashMap::new();
+    let mut queue: VecDeque<i64> = VecDeque::new();
+    visited.insert(currency_id, (currency_id, 1.0, 0));
+    queue.push_back(currency_id);
+
+    while let Some(current_id) = queue.pop_front() {
+        let hops = visited[&current_id].2;
+        if hops == max_hops {
+            continue;
+        }
+        let Some(adjacent) = graph.get(&current_id) else {
+            continue;
+        };
+        for &next_id in adjacent {
+            if visited.contains_key(&next_id) {
+                continue;
+            }
+            let Some((Some(rate), _)) = get_leg_rate(&data_map, current_id, next_id, at, find_rate)
+            else {
+                continue;
+            };
+            visited.insert(next_id, (current_id, rate, hops + 1));
+            if next_id == to_currency_id {
+                queue.clear();
+                break;
+            }
+            queue.push_back(next_id);
+        }
+    }
+
+    visited.get(&to_currency_id)?;
+
+    let mut path = vec![to_currency_id];
+    let mut rate = 1.0;
+    let mut current_id = to_currency_id;
+    while current_id != currency_id {
+        let &(previous_id, leg_rate, _) = &visited[&current_id];
+        rate *= leg_rate;
+        path.push(previous_id);
+        current_id = previous_id;
+    }
+    path.reverse();
+
+    Some((rate, path))
+}
+
+extension_sql!(
+    r#"
+CREATE TYPE kq_fx_quote AS (
+    bid double precision,
+    mid double precision,
+    ask double precision
+);
+"#,
+    name = "kq_fx_quote",
+    bootstrap
+);
+
+/// Returns the quote of a pair as a `kq_fx_quote`, NULL when there is no rate.
+#[pg_extern(parallel_safe, stable)]
+fn kq_fx_get_quote(
+    currency_id: i64,
+    to_currency_id: i64,
+    date: PgDate,
+) -> Option<composite_type!('static, "kq_fx_quote")> {
+    let quote = if currency_id == to_currency_id {
+        Quote::one()
+    } else {
+        get_quote(ensure_cache_populated(), currency_id, to_currency_id, date)?
+    };
+    let mut tuple = PgHeapTuple::new_composite_type("kq_fx_quote")
+        .unwrap_or_else(|err| error!("cannot create kq_fx_quote - {err}"));
+    for (name, rate) in [("bid", quote.bid), ("mid", quote.mid), ("ask", quote.ask)] {
+        tuple
+            .set_by_name(name, rate)
+            .unwrap_or_else(|err| error!("cannot set kq_fx_quote.{name} - {err}"));
+    }
+    Some(tuple)
+}
+
+/// Looks up the quote of a pair, `slot` is the cache from `ensure_cache_populated`.
+fn get_quote(slot: usize, currency_id: i64, to_currency_id: i64, date: PgDate) -> Option<Quote> {
+    let quote = get_rate(
+        slot,
+        currency_id,
+        to_currency_id,
+        end_of_day(date),
+        find_quote,
+        None,
+        None,
+    )
+    .0;
+    check_rate_found(quote, currency_id, to_currency_id, date)
+}
+
+#[pg_extern(parallel_safe, stable)]
+fn kq_fx_get_rate_history(
+    currency_id: i64,
+    to_currency_id: i64,
+    start_date: PgDate,
+    end_date: PgDate,
+    daily: default!(bool, false),
+) -> TableIterator<'static, (name!(date, PgDate), name!(rate, Option<f64>))> {
+    let slot = ensure_cache_populated();
+
+    let (start, end) = (start_date.to_pg_epoch_days(), end_date.to_pg_epoch_days());
+    let dates: Vec<StoreDate> = if daily {
+        if !start_date.is_finite() || !end_date.is_finite() {
+            error!("daily history requires finite dates: {start_date} to {end_date}");
+        }
+        if end as i64 - start as i64 >= MAX_SERIES_ROWS as i64 {
+            error!("daily history exceeds {MAX_SERIES_ROWS} rows: {start_date} to {end_date}");
+        }
+        (start..=end).collect()
+    } else {
+        get_rate_dates(slot, currency_id, to_currency_id)
+            .into_iter()
+            .filter(|date| (start..=end).contains(date))
+            .collect()
+    };
+
+    let rates = get_rate_series(slot, currency_id, to_currency_id, &dates);
+    let history: Vec<_> = dates
+        .into_iter()
+        .zip(rates)
+        .filter_map(|(date, rate)| {
+            let date = unsafe { PgDate::from_pg_epoch_days(date) };
+            if daily {
+                Some((
+                    date,
+                    check_rate_found(rate, currency_id, to_currency_id, date),
+                ))
+            } else {
+                // cached dates without a rate in effect, past their validity or too stale
+                Some((date, Some(rate?)))
+            }
+        })
+        .collect();
+    TableIterator::new(history)
+}
+
+#[pg_extern(parallel_safe, stable)]
+fn kq_fx_rate_series(
+    currency_id: i64,
+    to_currency_id: i64,
+    start_date: PgDate,
+    end_date: PgDate,
+    step: default!(Interval, "'1 day'"),
+) -> TableIterator<'static, (name!(date, PgDate), name!(rate, Option<f64>))> {
+    if step.micros() != 0
+        || step.months() < 0
+        || step.days() < 0
+        || step.months() + step.days() == 0
+    {
+        error!("step must be a positive number of days or months: {step}");
+    }
+    if !start_date.is_finite() || !end_date.is_finite() {
+        error!("series requires finite dates: {start_date} to {end_date}");
+    }
+
+    // Steps are added one after the other, as in generate_series
+    let end = end_date.to_pg_epoch_days();
+    let mut dates = Vec::new();
+    let mut current = Timestamp::from(start_date);
+    while let Some((date, _)) = split_timestamp(current) {
+        if date > end {
+            break;
+        }
+        if dates.len() == MAX_SERIES_ROWS {
+            error!("series exceeds {MAX_SERIES_ROWS} rows: {start_date} to {end_date} by {step}");
+        }
+        dates.push(date);
+        current = current + step;
+    }
+
+    let rates = if currency_id == to_currency_id {
+        vec![Some(1.0); dates.len()]
+    } else {
+        let slot = ensure_cache_populated();
+        get_rate_series(slot, currency_id, to_currency_id, &dates)
+    };
+    let series: Vec<_> = dates
+        .into_iter()
+        .zip(rates)
+        .map(|(date, rate)| {
+            let date = unsafe { PgDate::from_pg_epoch_days(date) };
+            (
+                date,
+                check_rate_found(rate, currency_id, to_currency_id, date),
+            )
+        })
+        .collect();
+    TableIterator::new(series)
+}
+
+/// Rates in effect at the end of ASC ordered dates, walking the entries once.
+fn rates_at_dates(entries: &[StoreRateEntry], dates: &[StoreDate]) -> Vec<Option<f64>> {
+    let mut index = 0;
+    dates
+        .iter()
+        .map(|&date| {
+            let at = (date, END_OF_DAY);
+            while index < entries.len() && entries[index].instant() <= at {
+                index += 1;
+            }
+            entry_in_effect(entries, index, at).map(|entry| entry.rate)
+        })
+        .collect()
+}
+
+/// Rates of a single cached pair at the end of ASC ordered dates, as `get_leg_rate`.
+fn get_leg_series(
+    data_map: &CurrencyDataMap,
+    currency_id: i64,
+    to_currency_id: i64,
+    dates: &[StoreDate],
+) -> Option<Vec<Option<f64>>> {
+    if let Some(rates) = data_map.find_series((currency_id, to_currency_id), dates) {
+        return Some(rates);
+    }
+
+    if INVERSE_FALLBACK.get() {
+        if let Some(rates) = data_map.find_series((to_currency_id, currency_id), dates) {
+            let rates = rates.into_iter().map(|rate| rate?.inverse()).collect();
+            return Some(rates);
+        }
+    }
+
+    None
+}
+
+/// Dates of the cached entries of a single pair, as `get_leg_rate`.
+fn get_leg_dates(
+    data_map: &CurrencyDataMap,
+    currency_id: i64,
+    to_currency_id: i64,
+) -> Option<BTreeSet<StoreDate>> {
+    if let Some(dates) = data_map.dates((currency_id, to_currency_id)) {
+        return Some(dates);
+    }
+
+    if INVERSE_FALLBACK.get() {
+        return data_map.dates((to_currency_id, currency_id));
+    }
+
+    None
+}
+
+/// Dates of the cached entries `get_rate` resolves a pair from, the dates of both legs when it is
+/// triangulated. `slot` is the cache from `ensure_cache_populated`.
+fn get_rate_dates(slot: usize, currency_id: i64, to_currency_id: i64) -> BTreeSet<StoreDate> {
+    if currency_id == to_currency_id {
+        return BTreeSet::new();
+    }
+
+    let caches = CURRENCY_CACHE.share();
+    let maps = slot_maps(&caches, slot, None, None);
+    let data_map = &maps.data_map;
+    if let Some(dates) = get_leg_dates(data_map, currency_id, to_currency_id) {
+        return dates;
+    }
+
+    if let Some(pivot_id) = get_pivot_currency_id(&maps.xuid_map) {
+        if pivot_id != currency_id && pivot_id != to_currency_id {
+            let from_leg = get_leg_dates(data_map, currency_id, pivot_id);
+            let to_leg = get_leg_dates(data_map, pivot_id, to_currency_id);
+            if let (Some(from_dates), Some(to_dates)) = (from_leg, to_leg) {
+                return &from_dates | &to_dates;
+            }
+        }
+    }
+
+    BTreeSet::new()
+}
+
+/// Rates of a pair at the end of ASC ordered dates, as `get_rate` with `find_rate`. `slot` is the
+/// cache from `ensure_cache_populated`.
+fn get_rate_series(
+    slot: usize,
+    currency_id: i64,
+    to_currency_id: i64,
+    dates: &[StoreDate],
+) -> Vec<Option<f64>> {
+    if currency_id == to_currency_id {
+        return vec![Some(1.0); dates.len()];
+    }
+
+    let caches = CURRENCY_CACHE.share();
+    let maps = slot_maps(&caches, slot, None, None);
+    let data_map = &maps.data_map;
+    if let Some(rates) = get_leg_series(data_map, currency_id, to_currency_id, dates) {
+        return rates;
+    }
+
+    if let Some(pivot_id) = get_pivot_currency_id(&maps.xuid_map) {
+        if pivot_id != currency_id && pivot_id != to_currency_id {
+            let from_leg = get_leg_series(data_map, currency_id, pivot_id, dates);
+            let to_leg = get_leg_series(data_map, pivot_id, to_currency_id, dates);
+            if let (Some(from_rates), Some(to_rates)) = (from_leg, to_leg) {
+                return from_rates
+                    .into_iter()
+                    .zip(to_rates)
+                    .map(|(from_rate, to_rate)| Some(from_rate?.compose(to_rate?)))
+                    .collect();
+            }
+        }
+    }
+
+    vec![None; dates.len()]
+}
+
+#[pg_extern(parallel_safe, stable)]
+fn kq_fx_get_rate_path(
+    currency_id: i64,
+    to_currency_id: i64,
+    date: PgDate,
+) -> TableIterator<'static, (name!(rate, f64), name!(path, Vec<i64>))> {
+    if currency_id == to_currency_id {
+        return TableIterator::new(Some((1.0, vec![currency_id])));
+    }
+
+    let slot = ensure_cache_populated();
+    TableIterator::new(get_rate_path(
+        slot,
+        currency_id,
+        to_currency_id,
+        end_of_day(date),
+    ))
+}
+
+#[pg_extern(parallel_safe, stable)]
+fn kq_fx_get_rate_xuid(
+    currency_xuid: String,
+    to_currency_xuid: String,
+    date: PgDate,
+) -> Option<f64> {
+    if currency_xuid.eq(&to_currency_xuid) {
+        return Some(1.0);
+    }
+
+    let slot = ensure_cache_populated();
+    let (from_id, to_id) = get_currency_ids(slot, &currency_xuid, &to_currency_xuid);
+    let rate = get_rate(
+        slot,
+        from_id,
+        to_id,
+        end_of_day(date),
+        find_rate,
+        None,
+        None,
+    )
+    .0;
+    check_rate_found(rate, from_id, to_id, date)
+}
+
+/// Resolves the currency ids of a pair of xuids, `slot` is the cache from
+/// `ensure_cache_populated`.
+fn get_currency_ids(slot: usize, currency_xuid: &str, to_currency_xuid: &str) -> (i64, i64) {
+    let currency_xuid = CurrencyXuid::from(heapless::String::from_str(currency_xuid).unwrap());
+    let to_currency_xuid =
+        CurrencyXuid::from(heapless::String::from_str(to_currency_xuid).unwrap());
+
+    let caches = CURRENCY_CACHE.share();
+    let xuid_map = slot_maps(&caches, slot, None, None).xuid_map;
+    let from_id = match xuid_map.get(&currency_xuid) {
+        None => {
+            error!("From currency xuid not found: {currency_xuid}")
+        }
+        Some(currency_id) => currency_id,
+    };
+    let to_id = match xuid_map.get(&to_currency_xuid) {
+        None => {
+            error!("Target currency xuid not found: {to_currency_xuid}")
+        }
+        Some(currency_id) => currency_id,
+    };
+    (*from_id, *to_id)
+}
+
+/// Decimal places of the currency, used to round converted amounts. `slot` is the cache from
+/// `ensure_cache_populated`.
+fn get_minor_units(slot: usize, currency_id: i64) -> i32 {
+    let caches = CURRENCY_CACHE.share();
+    let minor_units = slot_maps(&caches, slot, None, None)
+        .minor_units_map
+        .get(&currency_id)
+        .copied();
+    minor_units.unwrap_or(DEFAULT_MINOR_UNITS)
+}
+
+#[pg_extern(parallel_safe, stable)]
+fn kq_fx_convert(
+    amount: f64,
+    currency_id: i64,
+    to_currency_id: i64,
+    date: PgDate,
+    round: default!(bool, false),
+    side: default!(&str, "'mid'"),
+) -> Option<f64> {
+    let side = QuoteSide::from_name(side);
+    // the cache is only needed to round
+    if currency_id == to_currency_id && !round {
+        return Some(amount);
+    }
+
+    let slot = ensure_cache_populated();
+    convert(slot, amount, currency_id, to_currency_id, date, round, side)
+}
+
+#[pg_extern(parallel_safe, stable, name = "kq_fx_convert")]
+fn kq_fx_convert_numeric(
+    amount: AnyNumeric,
+    currency_id: i64,
+    to_currency_id: i64,
+    date: PgDate,
+    round: default!(bool, false),
+    side: default!(&str, "'mid'"),
+) -> Option<AnyNumeric> {
+    let side = QuoteSide::from_name(side);
+    // the cache is only needed to round
+    if currency_id == to_currency_id && !round {
+        return Some(amount);
+    }
+
+    let slot = ensure_cache_populated();
+    convert_numeric(slot, amount, currency_id, to_currency_id, date, round, side)
+}
+
+#[pg_extern(parallel_safe, stable)]
+fn kq_fx_convert_xuid(
+    amount: f64,
+    currency_xuid: String,
+    to_currency_xuid: String,
+    date: PgDate,
+    round: default!(bool, false),
+    side: default!(&str, "'mid'"),
+) -> Option<f64> {
+    let side = QuoteSide::from_name(side);
+    let slot = ensure_cache_populated();
+    let (from_id, to_id) = get_currency_ids(slot, &currency_xuid, &to_currency_xuid);
+    convert(slot, amount, from_id, to_id, date, round, side)
+}
+
+#[pg_extern(parallel_safe, stable, name = "kq_fx_convert_xuid")]
+fn kq_fx_convert_xuid_numeric(
+    amount: AnyNumeric,
+    currency_xuid: String,
+    to_currency_xuid: String,
+    date: PgDate,
+    round: default!(bool, false),
+    side: default!(&str, "'mid'"),
+) -> Option<AnyNumeric> {
+    let side = QuoteSide::from_name(side);
+    let slot = ensure_cache_populated();
+    let (from_id, to_id) = get_currency_ids(slot, &currency_xuid, &to_currency_xuid);
+    convert_numeric(slot, amount, from_id, to_id, date, round, side)
+}
+
+/// Converts an amount at a side of the quote of a pair, rounded to the minor units of
+/// `to_currency_id` when `round`. `slot` is the cache from `ensure_cache_populated`.
+fn convert(
+    slot: usize,
+    amount: f64,
+    currency_id: i64,
+    to_currency_id: i64,
+    date: PgDate,
+    round: bool,
+    side: QuoteSide,
+) -> Option<f64> {
+    let rate = match side {
+        QuoteSide::Mid => {
+            let at = end_of_day(date);
+            let rate = get_rate(slot, currency_id, to_currency_id, at, find_rate, None, None).0;
+            check_rate_found(rate, currency_id, to_currency_id, date)?
+        }
+        side => side.rate(get_quote(slot, currency_id, to_currency_id, date)?),
+    };
+    let converted = amount * rate;
+    if !round {
+        return Some(converted);
+    }
+    let scale = 10f64.powi(get_minor_units(slot, to_currency_id));
+    Some((converted * scale).round() / scale)
+}
+
+/// `convert` of a numeric amount, at the exact rate for the mid side.
+fn convert_numeric(
+    slot: usize,
+    amount: AnyNumeric,
+    currency_id: i64,
+    to_currency_id: i64,
+    date: PgDate,
+    round: bool,
+    side: QuoteSide,
+) -> Option<AnyNumeric> {
+    // Bid and ask rates are kept as float8, converted by their shortest decimal form
+    let rate = match side {
+        QuoteSide::Mid => {
+            let at = end_of_day(date);
+            let rate = get_rate(
+                slot,
+                currency_id,
+                to_currency_id,
+                at,
+                find_exact_rate,
+                None,
+                None,
+            )
+            .0;
+            check_rate_found(rate, currency_id, to_currency_id, date)?
+        }
+        side => {
+            float_rate_to_numeric(side.rate(get_quote(slot, currency_id, to_currency_id, date)?))
+        }
+    };
+    let converted = amount * rate;
+    if !round {
+        return Some(converted);
+    }
+    unsafe {
+        direct_function_call(
+            pg_sys::numeric_round,
+            &[
+                converted.into_datum(),
+                get_minor_units(slot, to_currency_id).into_datum(),
+            ],
+        )
+    }
+}
+
+#[pg_extern(parallel_safe, immutable)]
+fn kq_get_arr_value(
+    dates: Vec<PgDate>,
+    values: Vec<f64>,
+    date: PgDate,
+    default_value: Option<f64>,
+) -> Option<f64> {
+    if dates.is_empty() || values.is_empty() {
+        return default_value;
+    }
+
+    if dates.len() != values.len() {
+        error!("dates and values arrays does not have the same quantity of elements")
+    }
+
+    let dates: Vec<i32> = dates.iter().map(|date| date.to_pg_epoch_days()).collect();
+    let date = date.to_pg_epoch_days();
+
+    let pos = match dates.binary_search(&date) {
+        Ok(idx) => idx, // exact match
+        Err(idx) => {
+            if idx == 0 {
+                // date precedes first element
+                return default_value;
+            } else {
+                // <= value
+                idx - 1
+            }
+        }
+    };
+
+    values.get(pos).copied().or(default_value)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::PgDate;
+
+    extension_sql_file!("../sql/test_data.sql");
+
+    #[pg_test]
+    fn test_validate_db() {
+        crate::kq_fx_invalidate_cache();
+        assert_eq!(
+            "Database is compatible with the extension.",
+            crate::kq_fx_check_db()
+        );
+    }
+
+    #[pg_test]
+    fn test_get_rate_same_id() {
+        assert_eq!(
+            Some(1.0),
+            crate::kq_fx_get_rate(1, 1, PgDate::new(2015, 5, 1).unwrap())
+        );
+    }
+
+    #[pg_test]
+    fn test_always_ok() {
+        assert!(1 == 1);
+    }
+
+    #[pg_test]
+    fn test_get_rate_by_id() {
+        assert_eq!(
+            Some(1.2092987606763552f64),
+            crate::kq_fx_get_rate(2, 1, PgDate::new(2019, 12, 1).unwrap())
+        );
+        assert_eq!(
+            Some(1.6285458614035657f64),
+            crate::kq_fx_get_rate(
+                3590000203070,
+                3590000231158,
+                PgDate::new(2030, 1, 10).unwrap()
+            )
+        );
+    }
+
+    #[pg_test]
+    fn test_get_rate_by_xuid() {
+        assert_eq!(
+            Some(0.7335380076416401f64),
+            // 1 -> 2
+            crate::kq_fx_get_rate_xuid(
+                "usd".to_string(),
+                "cad".to_string(),
+                PgDate::new(2014, 2, 1).unwrap()
+            )
+        );
+        assert_eq!(
+            Some(1.6285458614035657f64),
+            // 3590000203070 -> 3590000231158
+            crate::kq_fx_get_rate_xuid(
+                "aud".to_string(),
+                "nzd".to_string(),
+                PgDate::new(2030, 1, 10).unwrap()
+            )
+        );
+    }
+
+    #[pg_test]
+    fn test_convert() {
+        let date = PgDate::new(2024, 3, 15).unwrap();
+        // 2 -> 1: 1.3539
+        assert_eq!(
+            Some(100.0 * 1.3539),
+            crate::kq_fx_convert(100.0, 2, 1, date, false, "mid")
+        );
+        assert_eq!(
+            Some(135.39),
+            crate::kq_fx_convert(100.0, 2, 1, date, true, "mid")
+        );
+        assert_eq!(
+            Some(135.39),
+            crate::kq_fx_convert_xuid(
+                100.0,
+                "cad".to_string(),
+                "usd".to_string(),
+                date,
+                true,
+                "mid"
+            )
+        );
+        assert_eq!(None, crate::kq_fx_convert(100.0, 3, 2, date, false, "mid"));
+
+        assert_eq!(
+            Some(AnyNumeric::try_from("135.39").unwrap()),
+            crate::kq_fx_convert_numeric(AnyNumeric::from(100), 2, 1, date, true, "mid")
+        );
+        assert_eq!(
+            Some(AnyNumeric::try_from("135.39").unwrap()),
+            crate::kq_fx_convert_xuid_numeric(
+                AnyNumeric::from(100),
+                "cad".to_string(),
+                "usd".to_string(),
+                date,
+                false,
+                "mid"
+            )
+        );
+    }
+
+    #[pg_test]
+    fn test_convert_minor_units() {
+        let date = PgDate::new(2024, 3, 15).unwrap();
+        Spi::run("SET kq.currency.rate_set = 'plan_minor_units'").unwrap();
+        Spi::run(
+            r#"SET kq.currency.q2_get_currencies_xuid = '
+                SELECT id, xuid, ("attributes"->>''minor_units'')::int4 FROM {rate_set}.currency ORDER BY id'"#,
+        )
+        .unwrap();
+        crate::kq_fx_invalidate_cache();
+
+        // jpy has no minor units, 1 -> 4: 148.27
+        assert_eq!(
+            Some(14827.0),
+            crate::kq_fx_convert(100.0, 1, 4, date, true, "mid")
+        );
+        assert_eq!(
+            Some(AnyNumeric::from(14827)),
+            crate::kq_fx_convert_numeric(AnyNumeric::from(100), 1, 4, date, true, "mid")
+        );
+        // currencies without minor units are rounded to 2 decimal places
+        assert_eq!(
+            Some(135.39),
+            crate::kq_fx_convert(100.0, 2, 1, date, true, "mid")
+        );
+
+        // rounded the same way when converting to the same currency on an empty cache
+        crate::kq_fx_invalidate_cache();
+        assert_eq!(
+            Some(1235.0),
+            crate::kq_fx_convert(1234.56, 4, 4, date, true, "mid")
+        );
+    }
+
+    #[pg_test]
+    fn test_get_rate_numeric() {
+        assert_eq!(
+            Some(AnyNumeric::try_from("1.3539").unwrap()),
+            crate::kq_fx_get_rate_numeric(2, 1, PgDate::new(2024, 3, 15).unwrap())
+        );
+        assert_eq!(
+            Some(AnyNumeric::try_from("1.2092987606763552").unwrap()),
+            crate::kq_fx_get_rate_numeric(2, 1, PgDate::new(2019, 12, 1).unwrap())
+        );
+        assert_eq!(
+            None,
+            crate::kq_fx_get_rate_numeric(2, 1, PgDate::new(1999, 1, 1).unwrap())
+        );
+    }
+
+    #[pg_test]
+    fn test_exact_rates() {
+        Spi::run("SET kq.currency.rate_set = 'plan_exact_rates'").unwrap();
+        Spi::run(
+            r#"SET kq.currency.q3_get_currency_entries = '
+                SELECT currency_id, to_currency_id, "date", rate::numeric FROM {rate_set}.fx_rate
+                UNION ALL
+                VALUES (3::int8, 2::int8, ''2024-01-01''::date, 1.1234567890123456789::numeric),
+                    (4, 2, ''2024-01-01'', 1e30)
+                ORDER BY 1, 2, 3'"#,
+        )
+        .unwrap();
+        let date = PgDate::new(2024, 1, 15).unwrap();
+
+        // converted from float8 when disabled
+        assert_eq!(
+            Some(AnyNumeric::try_from("1.1234567890123457").unwrap()),
+            crate::kq_fx_get_rate_numeric(3, 2, date)
+        );
+
+        Spi::run("SET kq.currency.exact_rates = on").unwrap();
+        assert_eq!("Cache refreshed.", crate::kq_fx_refresh_cache());
+        assert_eq!(
+            Some(AnyNumeric::try_from("1.123456789012345679").unwrap()),
+            crate::kq_fx_get_rate_numeric(3, 2, date)
+        );
+        // too large for the fixed-point copy
+        assert_eq!(
+            Some(AnyNumeric::try_from("1000000000000000000000000000000").unwrap()),
+            crate::kq_fx_get_rate_numeric(4, 2, date)
+        );
+
+        crate::kq_fx_invalidate_cache();
+    }
+
+    #[pg_test]
+    fn test_parse_exact_rate() {
+        let one = 10i128.pow(crate::EXACT_RATE_SCALE);
+        assert_eq!(Some(one), crate::parse_exact_rate("1"));
+        assert_eq!(Some(-one / 2), crate::parse_exact_rate("-0.5"));
+        assert_eq!(Some(13539 * one / 10000), crate::parse_exact_rate("1.3539"));
+        // rounded past the scale
+        assert_eq!(Some(1), crate::parse_exact_rate("0.0000000000000000005"));
+        assert_eq!(Some(0), crate::parse_exact_rate("0.0000000000000000004"));
+        assert_eq!(None, crate::parse_exact_rate("NaN"));
+        assert_eq!(
+            AnyNumeric::try_from("-1.3539").unwrap(),
+            crate::exact_rate_to_numeric(-13539 * one / 10000)
+        );
+    }
+
+    #[pg_test]
+    fn test_get_rate_triangulated() {
+        let date = PgDate::new(2024, 1, 15).unwrap();
+        // 3 -> 2 is not cached
+        assert_eq!(None, crate::kq_fx_get_rate(3, 2, date));
+        assert_eq!("not_found", crate::kq_fx_get_rate_source(3, 2, date));
+
+        Spi::run("SET kq.currency.pivot_currency = 'usd'").unwrap();
+        let expected =
+            crate::kq_fx_get_rate(3, 1, date).unwrap() * crate::kq_fx_get_rate(1, 2, date).unwrap();
+        assert_eq!(Some(expected), crate::kq_fx_get_rate(3, 2, date));
+        assert_eq!("triangulated", crate::kq_fx_get_rate_source(3, 2, date));
+        assert_eq!("direct", crate::kq_fx_get_rate_source(3, 1, date));
+
+        // a pivot missing from the rate set disables triangulation only
+        Spi::run("SET kq.currency.pivot_currency = 'chf'").unwrap();
+        assert_eq!(None, crate::kq_fx_get_rate(3, 2, date));
+        assert_eq!(Some(0.5366), crate::kq_fx_get_rate(3, 1, date));
+    }
+
+    #[pg_test]
+    fn test_get_rate_inverse() {
+        let date = PgDate::new(2024, 1, 15).unwrap();
+        // only 3 -> 1 is cached
+        assert_eq!(None, crate::kq_fx_get_rate(1, 3, date));
+
+        Spi::run("SET kq.currency.inverse_fallback = on").unwrap();
+        assert_eq!(Some(1.0 / 0.5366), crate::kq_fx_get_rate(1, 3, date));
+        assert_eq!("inverse", crate::kq_fx_get_rate_source(1, 3, date));
+        // direct pairs take precedence
+        assert_eq!("direct", crate::kq_fx_get_rate_source(1, 2, date));
+        // zero rates have no inverse, 3590000231158 -> 3 is 0
+        assert_eq!(None, crate::kq_fx_get_rate(3, 3590000231158, date));
+    }
+
+    #[pg_test]
+    fn test_get_rate_path() {
+        let date = PgDate::new(2024, 1, 15).unwrap();
+        // 3 -> 1 -> 2
+        let (rate, path) = crate::kq_fx_get_rate_path(3, 2, date).next().unwrap();
+        assert_eq!(vec![3, 1, 2], path);
+        assert_eq!(
+            crate::kq_fx_get_rate(3, 1, date).unwrap() * crate::kq_fx_get_rate(1, 2, date).unwrap(),
+            rate
+        );
+        // 1 -> 3 is only reachable with the inverse fallback
+        assert_eq!(None, crate::kq_fx_get_rate_path(1, 3, date).next());
+
+        Spi::run("SET kq.currency.max_hops = 1").unwrap();
+        assert_eq!(None, crate::kq_fx_get_rate_path(3, 2, date).next());
+    }
+
+    #[pg_test]
+    fn test_get_rate_interpolated() {
+        // 2023-11-01: 1.3709, 2023-12-01: 1.3431
+        assert_eq!(
+            Some(1.3709 + (1.3431 - 1.3709) * 0.5),
+            crate::kq_fx_get_rate_interpolated(2, 1, PgDate::new(2023, 11, 16).unwrap())
+        );
+        assert_eq!(
+            Some(1.3431),
+            crate::kq_fx_get_rate_interpolated(2, 1, PgDate::new(2023, 12, 1).unwrap())
+        );
+        assert_eq!(
+            None,
+            crate::kq_fx_get_rate_interpolated(2, 1, PgDate::new(1999, 1, 1).unwrap())
+        );
+        // Max Date: 2024-03-01
+        assert_eq!(
+            Some(1.3539),
+            crate::kq_fx_get_rate_interpolated(2, 1, PgDate::new(2024, 3, 30).unwrap())
+        );
+
+        Spi::run("SET kq.currency.extrapolate = on").unwrap();
+        // 2024-02-01: 1.3501, 2024-03-01: 1.3539
+        assert_eq!(
+            Some(1.3501 + (1.3539 - 1.3501) * 2.0),
+            crate::kq_fx_get_rate_interpolated(2, 1, PgDate::new(2024, 3, 30).unwrap())
+        );
+    }
+
+    #[pg_test]
+    fn test_try_get_less_than_min_date() {
+        assert_eq!(
+            None,
+            crate::kq_fx_get_rate(2, 1, PgDate::new(1999, 1, 1).unwrap())
+        );
+    }
+
+    #[pg_test]
+    fn test_try_get_greater_than_max_date() {
+        assert_eq!(
+            Some(1.3539f64),
+            crate::kq_fx_get_rate(
+                2,
+                1,
+                PgDate::new(2100, 1, 1).unwrap() // Max Date: 2024-03-01
+            )
+        );
+    }
+
+    #[pg_test]
+    fn test_out_of_range_policy() {
+        // Min Date: 2016-01-01, Max Date: 2024-03-01
+        let before = PgDate::new(1999, 1, 1).unwrap();
+        let after = PgDate::new(2100, 1, 1).unwrap();
+
+        Spi::run("SET kq.currency.out_of_range = 'null'").unwrap();
+        assert_eq!(None, crate::kq_fx_get_rate(2, 1, before));
+        assert_eq!(None, crate::kq_fx_get_rate(2, 1, after));
+
+        Spi::run("SET kq.currency.out_of_range = 'clamp'").unwrap();
+        assert_eq!(
+            Some(1.4751612948931951f64),
+            crate::kq_fx_get_rate(2, 1, before)
+        );
+        assert_eq!(Some(1.3539f64), crate::kq_fx_get_rate(2, 1, after));
+
+        Spi::run("SET kq.currency.out_of_range = 'carry'").unwrap();
+        Spi::run("SET kq.currency.max_staleness_days = 30").unwrap();
+        assert_eq!(None, crate::kq_fx_get_rate(2, 1, after));
+        assert_eq!(
+            Some(1.3539f64),
+            crate::kq_fx_get_rate(2, 1, PgDate::new(2024, 3, 31).unwrap())
+        );
+        assert_eq!(
+            None,
+            crate::kq_fx_get_rate(2, 1, PgDate::positive_infinity())
+        );
+    }
+
+    #[pg_test]
+    fn test_frozen_map() {
+        use crate::shared_map::{FrozenLayout, FrozenVecMap};
+        use std::collections::BTreeMap;
+
+        let map = BTreeMap::from([(2i64, vec![20i64, 21]), (1, vec![10]), (3, vec![])]);
+        let mut layout = FrozenLayout::new::<FrozenVecMap<i64, i64>>();
+        let frozen = FrozenVecMap::reserve(&mut layout, &map);
+        let mut buffer = vec![0u128; layout.size().div_ceil(16)];
+        let base = buffer.as_mut_ptr() as *mut u8;
+        let frozen_ref = unsafe {
+            frozen.write(base, &map);
+            frozen.get(base)
+        };
+        assert_eq!(Some(&[10][..]), frozen_ref.get(&1));
+        assert_eq!(Some(&[20, 21][..]), frozen_ref.get(&2));
+        assert_eq!(Some(&[] as &[i64]), frozen_ref.get(&3));
+        assert_eq!(None, frozen_ref.get(&4));
+        assert_eq!(
+            vec![&1, &2, &3],
+            frozen_ref.iter().map(|(key, _)| key).collect::<Vec<_>>()
+        );
+    }
+
+    #[pg_test]
+    fn test_cache_slot() {
+        Spi::run("SET kq.currency.rate_set = 'plan_cache_slot'").unwrap();
+        crate::kq_fx_populate_cache();
+        let slot = crate::find_cache_slot("plan_cache_slot").unwrap();
+        assert_eq!(
+            unsafe { pg_sys::MyDatabaseId }.as_u32(),
+            crate::CURRENCY_KEYS.share()[slot].database
+        );
+        assert!(crate::CURRENCY_CONTROL.share()[slot].cache_filled);
+
+        crate::kq_fx_invalidate_cache();
+    }
+
+    #[pg_test]
+    fn test_release_dropped_caches() {
+        // a slot left by a database that no longer exists
+        let dropped = crate::CacheKey {
+            database: u32::MAX,
+            ..crate::CacheKey::new("plan").unwrap()
+        };
+        let slot = crate::take_cache_slot(&dropped).unwrap();
+
+        assert!(crate::release_dropped_caches());
+        assert_eq!(0, crate::CURRENCY_KEYS.share()[slot].database);
+        assert!(!crate::release_dropped_caches());
+    }
+
+    #[pg_test]
+    fn test_rate_set() {
+        Spi::run("SET kq.currency.rate_set = 'plan_rate_set'").unwrap();
+        let date = PgDate::new(2024, 1, 15).unwrap();
+        assert_eq!(Some(1.3425), crate::kq_fx_get_rate(2, 1, date));
+        assert_eq!(None, crate::kq_fx_get_rate(2, 3, date));
+
+        Spi::run("SET kq.currency.rate_set = 'plan_eu'").unwrap();
+        assert_eq!(Some(0.6843), crate::kq_fx_get_rate(2, 3, date));
+        assert_eq!(None, crate::kq_fx_get_rate(2, 1, date));
+        let slot = crate::find_cache_slot("plan_eu");
+        assert!(slot.is_some());
+        assert_ne!(slot, crate::find_cache_slot("plan_rate_set"));
+        // invalidation only affects the current rate set, and releases its slot
+        crate::kq_fx_invalidate_cache();
+        assert_eq!(None, crate::find_cache_slot("plan_eu"));
+
+        Spi::run("SET kq.currency.rate_set = 'plan_rate_set'").unwrap();
+        assert!(crate::is_cache_filled(
+            crate::find_cache_slot("plan_rate_set").unwrap()
+        ));
+        assert_eq!(Some(1.3425), crate::kq_fx_get_rate(2, 1, date));
+
+        crate::kq_fx_invalidate_cache();
+    }
+
+    #[pg_test(error = "The current database is not compatible with the ketteQ FX extension.")]
+    fn test_rate_set_not_found() {
+        // no slot is taken by rate sets failing the validation
+        Spi::run("SET kq.currency.rate_set = 'plan_missing'").unwrap();
+        crate::kq_fx_get_rate(2, 1, PgDate::new(2024, 1, 15).unwrap());
+    }
+
+    #[pg_test]
+    fn test_rate_types() {
+        Spi::run("SET kq.currency.rate_set = 'plan_rate_types'").unwrap();
+        let date = PgDate::new(2024, 1, 15).unwrap();
+        Spi::run(
+            r#"SET kq.currency.q3_get_currency_entries = '
+                SELECT currency_id, to_currency_id, "date", rate, NULL::text AS rate_type FROM {rate_set}.fx_rate
+                UNION ALL
+                SELECT currency_id, to_currency_id, "date", rate * 2, ''budget'' FROM {rate_set}.fx_rate
+                ORDER BY 1, 2, 3'"#,
+        )
+        .unwrap();
+
+        assert_eq!(Some(1.3425), crate::kq_fx_get_rate(2, 1, date));
+        assert_eq!(
+            Some(1.3425 * 2.0),
+            crate::kq_fx_get_rate_by_type(2, 1, date, "budget")
+        );
+        assert_eq!(None, crate::kq_fx_get_rate_by_type(2, 1, date, "closing"));
+        assert_eq!(Some(1.3425), crate::kq_fx_get_rate_by_type(2, 1, date, ""));
+
+        Spi::run("SET kq.currency.rate_type = 'budget'").unwrap();
+        assert_eq!(Some(1.3425 * 2.0), crate::kq_fx_get_rate(2, 1, date));
+
+        crate::kq_fx_invalidate_cache();
+    }
+
+    #[pg_test]
+    fn test_scenarios() {
+        Spi::run("SET kq.currency.rate_set = 'plan_scenarios'").unwrap();
+        let date = PgDate::new(2024, 1, 15).unwrap();
+        Spi::run(
+            r#"SET kq.currency.q3_get_currency_entries = '
+                SELECT currency_id, to_currency_id, "date", rate, NULL::text AS scenario FROM {rate_set}.fx_rate
+                UNION ALL
+                SELECT 2, 1, ''2024-01-10'', 1.5, ''override''
+                ORDER BY 1, 2, 3'"#,
+        )
+        .unwrap();
+        Spi::run(
+            "SET kq.currency.q5_get_scenario_shocks = 'SELECT ''eur_up'', 2::int8, 1::int8, 1.05::float8'",
+        )
+        .unwrap();
+
+        assert_eq!(Some(1.3425), crate::kq_fx_get_rate(2, 1, date));
+        assert_eq!(
+            Some(1.5),
+            crate::kq_fx_get_rate_scenario(2, 1, date, "override", None)
+        );
+        // the scenario has no rate before its first date
+        assert_eq!(
+            Some(1.3431),
+            crate::kq_fx_get_rate_scenario(
+                2,
+                1,
+                PgDate::new(2023, 12, 15).unwrap(),
+                "override",
+                None
+            )
+        );
+        assert_eq!(
+            Some(1.3425 * 1.05),
+            crate::kq_fx_get_rate_scenario(2, 1, date, "eur_up", None)
+        );
+        assert_eq!(
+            Some(0.5366),
+            crate::kq_fx_get_rate_scenario(3, 1, date, "eur_up", None)
+        );
+        assert_eq!(
+            Some(1.3425),
+            crate::kq_fx_get_rate_scenario(2, 1, date, "missing", None)
+        );
+
+        Spi::run("SET kq.currency.scenario = 'override'").unwrap();
+        assert_eq!(Some(1.5), crate::kq_fx_get_rate(2, 1, date));
+
+        crate::kq_fx_invalidate_cache();
+    }
+
+    #[pg_test]
+    fn test_quotes() {
+        Spi::run("SET kq.currency.rate_set = 'plan_quotes'").unwrap();
+        let date = PgDate::new(2024, 1, 15).unwrap();
+        Spi::run(
+            r#"SET kq.currency.q3_get_currency_entries = '
+                SELECT currency_id, to_currency_id, "date", rate,
+                    CASE WHEN currency_id = 2 THEN rate + 0.01 END AS ask,
+                    CASE WHEN currency_id = 2 THEN rate - 0.01 END AS bid
+                FROM {rate_set}.fx_rate
+                ORDER BY 1, 2, 3'"#,
+        )
+        .unwrap();
+
+        let quote = crate::Quote {
+            bid: 1.3425 - 0.01,
+            mid: 1.3425,
+            ask: 1.3425 + 0.01,
+        };
+        let slot = crate::ensure_cache_populated();
+        assert_eq!(Some(quote), crate::get_quote(slot, 2, 1, date));
+        // pairs without quotes use the mid rate
+        assert_eq!(
+            Some(crate::Quote {
+                bid: 0.5366,
+                mid: 0.5366,
+                ask: 0.5366
+            }),
+            crate::get_quote(slot, 3, 1, date)
+        );
+        Spi::run("SET kq.currency.inverse_fallback = on").unwrap();
+        assert_eq!(
+            Some(crate::Quote {
+                bid: 1.0 / quote.ask,
+                mid: 1.0 / quote.mid,
+                ask: 1.0 / quote.bid
+            }),
+            crate::get_quote(slot, 1, 2, date)
+        );
+
+        assert_eq!(
+            Some(100.0 * quote.ask),
+            crate::kq_fx_convert(100.0, 2, 1, date, false, "ask")
+        );
+        assert_eq!(
+            Some(100.0 * quote.bid),
+            crate::kq_fx_convert(100.0, 2, 1, date, false, "bid")
+        );
+        assert_eq!(
+            Some(134.25),
+            crate::kq_fx_convert(100.0, 2, 1, date, true, "mid")
+        );
+        assert_eq!(
+            Ok(Some(quote.ask)),
+            Spi::get_one::<f64>("SELECT (kq_fx_get_quote(2, 1, '2024-01-15')).ask")
+        );
+        assert_eq!(
+            Ok(Some(true)),
+            Spi::get_one::<bool>("SELECT kq_fx_get_quote(2, 1, '1999-01-01') IS NULL")
+        );
+
+        crate::kq_fx_invalidate_cache();
+    }
+
+    #[pg_test]
+    fn test_effective_to() {
+        Spi::run("SET kq.currency.rate_set = 'plan_effective_to'").unwrap();
+        Spi::run(
+            r#"SET kq.currency.q3_get_currency_entries = '
+                SELECT currency_id, to_currency_id, "date", rate,
+                    CASE WHEN currency_id = 3 THEN "date" + 9 END AS effective_to
+                FROM {rate_set}.fx_rate
+                ORDER BY 1, 2, 3'"#,
+        )
+        .unwrap();
+
+        // (3,1) is valid from 2024-01-01 to 2024-01-10
+        assert_eq!(
+            Some(0.5366),
+            crate::kq_fx_get_rate(3, 1, PgDate::new(2024, 1, 10).unwrap())
+        );
+        assert_eq!(
+            None,
+            crate::kq_fx_get_rate(3, 1, PgDate::new(2024, 1, 11).unwrap())
+        );
+        // rows without effective_to are carried
+        assert_eq!(
+            Some(1.3425),
+            crate::kq_fx_get_rate(2, 1, PgDate::new(2024, 1, 15).unwrap())
+        );
+
+        crate::kq_fx_invalidate_cache();
+    }
+
+    #[pg_test]
+    fn test_intraday_rates() {
+        Spi::run("SET kq.currency.rate_set = 'plan_intraday'").unwrap();
+        Spi::run("SET TimeZone = 'UTC'").unwrap();
+        Spi::run(
+            r#"SET kq.currency.q3_get_currency_entries = '
+                SELECT currency_id, to_currency_id, "date"::timestamptz, rate FROM {rate_set}.fx_rate
+                UNION ALL
+                VALUES (2::int8, 1::int8, ''2024-01-15 09:00+00''::timestamptz, 1.35::float8),
+                    (2, 1, ''2024-01-15 15:00+00'', 1.36)
+                ORDER BY 1, 2, 3'"#,
+        )
+        .unwrap();
+
+        let at = |hour, time_zone| {
+            TimestampWithTimeZone::with_timezone(2024, 1, 15, hour, 0, 0.0, time_zone).unwrap()
+        };
+        assert_eq!(Some(1.3425), crate::kq_fx_get_rate_at(2, 1, at(8, "UTC")));
+        assert_eq!(Some(1.35), crate::kq_fx_get_rate_at(2, 1, at(10, "UTC")));
+        assert_eq!(
+            Some(1.35),
+            crate::kq_fx_get_rate_at(2, 1, at(12, "Europe/Istanbul"))
+        );
+        assert_eq!(
+            Some(1.36),
+            crate::kq_fx_get_rate_at_local(2, 1, Timestamp::new(2024, 1, 15, 16, 0, 0.0).unwrap())
+        );
+        // date lookups read the end of the day
+        assert_eq!(
+            Some(1.36),
+            crate::kq_fx_get_rate(2, 1, PgDate::new(2024, 1, 15).unwrap())
+        );
+        assert_eq!(
+            Some(1.3425),
+            crate::kq_fx_get_rate(2, 1, PgDate::new(2024, 1, 14).unwrap())
+        );
+        // the history has the last rate of each day
+        let date = PgDate::new(2024, 1, 15).unwrap();
+        assert_eq!(
+            vec![(date, Some(1.36))],
+            crate::kq_fx_get_rate_history(2, 1, date, date, false).collect::<Vec<_>>()
+        );
 
-    *CURRENCY_CONTROL.exclusive() = CurrencyControl {
-        cache_being_filled: false,
-        ..CurrencyControl::default()
-    };
+        crate::kq_fx_invalidate_cache();
+    }
 
-    xuid_map.clear();
-    "Cache invalidated."
-}
+    #[pg_test]
+    fn test_rate_history() {
+        let history: Vec<_> = crate::kq_fx_get_rate_history(
+            2,
+            1,
+            PgDate::new(2023, 11, 1).unwrap(),
+            PgDate::new(2024, 2, 15).unwrap(),
+            false,
+        )
+        .collect();
+        assert_eq!(
+            vec![
+                (PgDate::new(2023, 11, 1).unwrap(), Some(1.3709)),
+                (PgDate::new(2023, 12, 1).unwrap(), Some(1.3431)),
+                (PgDate::new(2024, 1, 1).unwrap(), Some(1.3425)),
+                (PgDate::new(2024, 2, 1).unwrap(), Some(1.3501)),
+            ],
+            history
+        );
 
-#[pg_extern(parallel_safe)]
-fn kq_fx_populate_cache() -> &'static str {
-    ensure_cache_populated();
-    "Cache populated."
-}
+        let daily: Vec<_> = crate::kq_fx_get_rate_history(
+            2,
+            1,
+            PgDate::new(2023, 12, 31).unwrap(),
+            PgDate::new(2024, 1, 2).unwrap(),
+            true,
+        )
+        .collect();
+        assert_eq!(
+            vec![
+                (PgDate::new(2023, 12, 31).unwrap(), Some(1.3431)),
+                (PgDate::new(2024, 1, 1).unwrap(), Some(1.3425)),
+                (PgDate::new(2024, 1, 2).unwrap(), Some(1.3425)),
+            ],
+            daily
+        );
 
-#[pg_extern(parallel_safe)]
-fn kq_fx_display_cache() -> TableIterator<
-    'static,
-    (
-        name!(currency_id, i64),
-        name!(to_currency_id, i64),
-        name!(date, PgDate),
-        name!(rate, f64),
-    ),
-> {
-    ensure_cache_populated();
-    let result_vec: Vec<(_, _, _, _)> = CURRENCY_DATA_MAP
-        .share()
-        .iter()
-        .flat_map(|((from_id, to_id), data_vec)| {
-            data_vec.iter().map(move |date_rate| unsafe {
-                let date = pgrx::datum::Date::from_pg_epoch_days(date_rate.0);
-                (*from_id, *to_id, date, date_rate.1)
-            })
-        })
+        let not_cached = crate::kq_fx_get_rate_history(
+            3,
+            2,
+            PgDate::new(2024, 1, 1).unwrap(),
+            PgDate::new(2024, 1, 2).unwrap(),
+            false,
+        );
+        assert_eq!(0, not_cached.count());
+
+        // the dates of both legs of a triangulated pair, without the ones before the first rate
+        Spi::run("SET kq.currency.pivot_currency = 'usd'").unwrap();
+        let triangulated: Vec<_> = crate::kq_fx_get_rate_history(
+            3,
+            2,
+            PgDate::new(2023, 12, 1).unwrap(),
+            PgDate::new(2024, 2, 15).unwrap(),
+            false,
+        )
         .collect();
-    TableIterator::new(result_vec)
-}
+        let dates = [
+            PgDate::new(2024, 1, 1).unwrap(),
+            PgDate::new(2024, 2, 1).unwrap(),
+        ];
+        assert_eq!(
+            dates
+                .map(|date| (date, crate::kq_fx_get_rate(3, 2, date)))
+                .to_vec(),
+            triangulated
+        );
+    }
 
-#[pg_extern(parallel_safe, immutable)]
-#[allow(clippy::comparison_chain)]
-fn kq_fx_get_rate(currency_id: i64, to_currency_id: i64, date: PgDate) -> Option<f64> {
-    if currency_id == to_currency_id {
-        return Some(1.0);
+    #[pg_test(error = "No rate available for (2,1) at 1999-01-01")]
+    fn test_rate_history_out_of_range_error() {
+        Spi::run("SET kq.currency.out_of_range = 'error'").unwrap();
+        crate::kq_fx_get_rate_history(
+            2,
+            1,
+            PgDate::new(1999, 1, 1).unwrap(),
+            PgDate::new(1999, 1, 2).unwrap(),
+            true,
+        );
     }
 
-    ensure_cache_populated();
+    #[pg_test]
+    fn test_rate_series() {
+        let series: Vec<_> = crate::kq_fx_rate_series(
+            2,
+            1,
+            PgDate::new(2023, 11, 15).unwrap(),
+            PgDate::new(2024, 2, 15).unwrap(),
+            Interval::from_months(1),
+        )
+        .collect();
+        assert_eq!(
+            vec![
+                (PgDate::new(2023, 11, 15).unwrap(), Some(1.3709)),
+                (PgDate::new(2023, 12, 15).unwrap(), Some(1.3431)),
+                (PgDate::new(2024, 1, 15).unwrap(), Some(1.3425)),
+                (PgDate::new(2024, 2, 15).unwrap(), Some(1.3501)),
+            ],
+            series
+        );
+        // matches the lookups of each day
+        let start = PgDate::new(2023, 12, 25).unwrap();
+        for (date, rate) in
+            crate::kq_fx_rate_series(2, 1, start, start + 14, Interval::from_days(1))
+        {
+            assert_eq!(crate::kq_fx_get_rate(2, 1, date), rate);
+        }
 
-    let date: i32 = date.to_pg_epoch_days();
-    if let Some(dates_rates) = CURRENCY_DATA_MAP
-        .share()
-        .get(&(currency_id, to_currency_id))
-    {
-        let &(first_date, first_rate) = dates_rates.first().unwrap();
-        if date < first_date {
-            return None;
-        } else if date == first_date {
-            return Some(first_rate);
-        }
-        let &(last_date, last_rate) = dates_rates.last().unwrap();
-        if date >= last_date {
-            return Some(last_rate);
-        }
-        let result = dates_rates.binary_search_by(|&(cache_date, _)| cache_date.cmp(&date));
-        match result {
-            Ok(index) => {
-                let rate = dates_rates[index].1;
-                Some(rate)
-            }
-            Err(index) => {
-                if index > 0 {
-                    let index = index - 1;
-                    let rate = dates_rates[index].1;
-                    Some(rate)
-                } else {
-                    None
-                }
+        assert_eq!(
+            3,
+            crate::kq_fx_rate_series(1, 1, start, start + 20, Interval::from_weeks(1))
+                .filter(|(_, rate)| *rate == Some(1.0))
+                .count()
+        );
+
+        // resolved as the lookups, through the inverse fallback and the pivot currency
+        Spi::run("SET kq.currency.inverse_fallback = on").unwrap();
+        Spi::run("SET kq.currency.pivot_currency = 'usd'").unwrap();
+        for (from_id, to_id) in [(1, 3), (3, 2), (2, 3)] {
+            let series: Vec<_> =
+                crate::kq_fx_rate_series(from_id, to_id, start, start + 14, Interval::from_days(1))
+                    .collect();
+            assert!(series.iter().any(|(_, rate)| rate.is_some()));
+            for (date, rate) in series {
+                assert_eq!(crate::kq_fx_get_rate(from_id, to_id, date), rate);
             }
         }
-    } else {
-        None
     }
-}
-
-#[pg_extern(parallel_safe, immutable)]
-fn kq_fx_get_rate_xuid(
-    currency_xuid: String,
-    to_currency_xuid: String,
-    date: PgDate,
-) -> Option<f64> {
-    let currency_xuid =
-        CurrencyXuid::from(heapless::String::from_str(currency_xuid.as_str()).unwrap());
-    let to_currency_xuid =
-        CurrencyXuid::from(heapless::String::from_str(to_currency_xuid.as_str()).unwrap());
 
-    if currency_xuid.eq(&to_currency_xuid) {
-        return Some(1.0);
+    #[pg_test(error = "No rate available for (2,1) at 1999-01-01")]
+    fn test_rate_series_out_of_range_error() {
+        Spi::run("SET kq.currency.out_of_range = 'error'").unwrap();
+        crate::kq_fx_rate_series(
+            2,
+            1,
+            PgDate::new(1999, 1, 1).unwrap(),
+            PgDate::new(1999, 1, 2).unwrap(),
+            Interval::from_days(1),
+        );
     }
 
-    ensure_cache_populated();
+    #[pg_test(error = "daily history exceeds 1000000 rows: 2024-01-01 to 9999-12-31")]
+    fn test_rate_history_too_long() {
+        crate::kq_fx_get_rate_history(
+            2,
+            1,
+            PgDate::new(2024, 1, 1).unwrap(),
+            PgDate::new(9999, 12, 31).unwrap(),
+            true,
+        );
+    }
 
-    let xuid_map = CURRENCY_XUID_MAP.share();
-    let from_id = match xuid_map.get(&currency_xuid) {
-        None => {
-            error!("From currency xuid not found: {currency_xuid}")
-        }
-        Some(currency_id) => currency_id,
-    };
-    let to_id = match xuid_map.get(&to_currency_xuid) {
-        None => {
-            error!("Target currency xuid not found: {to_currency_xuid}")
-        }
-        Some(currency_id) => currency_id,
-    };
-    kq_fx_get_rate(*from_id, *to_id, date)
-}
+    #[pg_test(error = "interpolation requires a finite date: infinity")]
+    fn test_get_rate_interpolated_infinite() {
+        Spi::run("SET kq.currency.extrapolate = on").unwrap();
+        crate::kq_fx_get_rate_interpolated(2, 1, PgDate::positive_infinity());
+    }
 
-#[pg_extern(parallel_safe, immutable)]
-fn kq_get_arr_value(
-    dates: Vec<PgDate>,
-    values: Vec<f64>,
-    date: PgDate,
-    default_value: Option<f64>,
-) -> Option<f64> {
-    if dates.is_empty() || values.is_empty() {
-        return default_value;
+    #[pg_test(error = "series exceeds 1000000 rows: 2024-01-01 to 9999-12-31 by 1 day")]
+    fn test_rate_series_too_long() {
+        crate::kq_fx_rate_series(
+            2,
+            1,
+            PgDate::new(2024, 1, 1).unwrap(),
+            PgDate::new(9999, 12, 31).unwrap(),
+            Interval::from_days(1),
+        );
     }
 
-    if dates.len() != values.len() {
-        error!("dates and values arrays does not have the same quantity of elements")
+    #[pg_test(error = "series requires finite dates: 2024-01-01 to infinity")]
+    fn test_rate_series_infinite() {
+        crate::kq_fx_rate_series(
+            2,
+            1,
+            PgDate::new(2024, 1, 1).unwrap(),
+            PgDate::positive_infinity(),
+            Interval::from_days(1),
+        );
     }
 
-    let dates: Vec<i32> = dates.iter().map(|date| date.to_pg_epoch_days()).collect();
-    let date = date.to_pg_epoch_days();
+    #[pg_test]
+    fn test_refresh_cache() {
+        Spi::run("SET kq.currency.rate_set = 'plan_refresh'").unwrap();
+        let date = PgDate::new(2024, 1, 15).unwrap();
+        assert_eq!(None, crate::kq_fx_get_rate(3590000203070, 1, date));
 
-    let pos = match dates.binary_search(&date) {
-        Ok(idx) => idx, // exact match
-        Err(idx) => {
-            if idx == 0 {
-                // date precedes first element
-                return default_value;
-            } else {
-                // <= value
-                idx - 1
-            }
-        }
-    };
+        Spi::run("INSERT INTO plan_refresh.fx_rate (currency_id, to_currency_id, \"date\", rate) VALUES (3590000203070, 1, '2024-01-01', 0.68)").unwrap();
+        assert_eq!("Cache refreshed.", crate::kq_fx_refresh_cache());
+        assert_eq!(Some(0.68), crate::kq_fx_get_rate(3590000203070, 1, date));
+        // other pairs are kept
+        assert_eq!(Some(0.5366), crate::kq_fx_get_rate(3, 1, date));
 
-    values.get(pos).copied().or(default_value)
-}
+        Spi::run(
+            "DELETE FROM plan_refresh.fx_rate WHERE currency_id = 3590000203070 AND to_currency_id = 1",
+        )
+        .unwrap();
+        crate::kq_fx_refresh_cache();
+        assert_eq!(None, crate::kq_fx_get_rate(3590000203070, 1, date));
+    }
 
-#[cfg(any(test, feature = "pg_test"))]
-#[pg_schema]
-mod tests {
-    use pgrx::prelude::*;
+    /// Runs outside of a test transaction, so the statements commit and the commit callback of the
+    /// trigger runs.
+    #[cfg(test)]
+    #[test]
+    fn test_invalidation_triggers() {
+        const GET_RATE: &str = "SELECT kq_fx_get_rate(3590000203070, 1, '2024-01-15')";
+        const INSERT_RATE: &str = "INSERT INTO plan_triggers.fx_rate (currency_id, to_currency_id, \"date\", rate) VALUES (3590000203070, 1, '2024-01-01', 0.68)";
+        pgrx_tests::run_test(
+            "test_always_ok",
+            None,
+            crate::pg_test::postgresql_conf_options(),
+        )
+        .unwrap();
+        let (mut client, _) = pgrx_tests::client().unwrap();
 
-    use crate::PgDate;
+        client
+            .batch_execute("SELECT kq_fx_install_triggers(ARRAY['plan_triggers.fx_rate'])")
+            .unwrap();
+        assert_eq!(
+            1,
+            client
+                .query_one(
+                    "SELECT count(*) FROM pg_trigger WHERE tgname = 'kq_fx_invalidate_cache'",
+                    &[]
+                )
+                .unwrap()
+                .get::<_, i64>(0)
+        );
+        client
+            .batch_execute("SET kq.currency.rate_set = 'plan_triggers'")
+            .unwrap();
+        assert_eq!(
+            None,
+            client
+                .query_one(GET_RATE, &[])
+                .unwrap()
+                .get::<_, Option<f64>>(0)
+        );
 
-    extension_sql_file!("../sql/test_data.sql");
+        // the cache is only marked stale at commit
+        client.batch_execute("BEGIN").unwrap();
+        client.batch_execute(INSERT_RATE).unwrap();
+        assert_eq!(
+            None,
+            client
+                .query_one(GET_RATE, &[])
+                .unwrap()
+                .get::<_, Option<f64>>(0)
+        );
+        client.batch_execute("ROLLBACK").unwrap();
+        assert_eq!(
+            None,
+            client
+                .query_one(GET_RATE, &[])
+                .unwrap()
+                .get::<_, Option<f64>>(0)
+        );
 
-    #[pg_test]
-    fn test_validate_db() {
-        crate::kq_fx_invalidate_cache();
+        client.batch_execute(INSERT_RATE).unwrap();
         assert_eq!(
-            "Database is compatible with the extension.",
-            crate::kq_fx_check_db()
+            Some(0.68),
+            client
+                .query_one(GET_RATE, &[])
+                .unwrap()
+                .get::<_, Option<f64>>(0)
         );
-    }
 
-    #[pg_test]
-    fn test_get_rate_same_id() {
+        // a load after the commit reads it, also in a transaction whose snapshot predates it
+        let (mut reader, _) = pgrx_tests::client().unwrap();
+        reader
+            .batch_execute(
+                "SET kq.currency.rate_set = 'plan_triggers'; BEGIN ISOLATION LEVEL REPEATABLE READ; SELECT 1",
+            )
+            .unwrap();
+        client
+            .batch_execute(
+                "UPDATE plan_triggers.fx_rate SET rate = 0.69 WHERE currency_id = 3590000203070",
+            )
+            .unwrap();
         assert_eq!(
-            Some(1.0),
-            crate::kq_fx_get_rate(1, 1, PgDate::new(2015, 5, 1).unwrap())
+            Some(0.69),
+            reader
+                .query_one(GET_RATE, &[])
+                .unwrap()
+                .get::<_, Option<f64>>(0)
+        );
+        reader.batch_execute("COMMIT").unwrap();
+
+        client
+            .batch_execute("SELECT kq_fx_uninstall_triggers(ARRAY['plan_triggers.fx_rate'])")
+            .unwrap();
+        assert_eq!(
+            0,
+            client
+                .query_one(
+                    "SELECT count(*) FROM pg_trigger WHERE tgname = 'kq_fx_invalidate_cache'",
+                    &[]
+                )
+                .unwrap()
+                .get::<_, i64>(0)
         );
+        client
+            .batch_execute("SELECT kq_fx_invalidate_cache()")
+            .unwrap();
     }
 
     #[pg_test]
-    fn test_always_ok() {
-        assert!(1 == 1);
+    fn test_refresh_pair() {
+        Spi::run("SET kq.currency.rate_set = 'plan_refresh_pair'").unwrap();
+        let date = PgDate::new(2024, 4, 15).unwrap();
+        assert_eq!(Some(1.3539f64), crate::kq_fx_get_rate(2, 1, date));
+
+        Spi::run("INSERT INTO plan_refresh_pair.fx_rate (currency_id, to_currency_id, \"date\", rate) VALUES (2, 1, '2024-04-01', 1.4), (3590000203070, 1, '2024-01-01', 0.68)").unwrap();
+        assert_eq!("Pair refreshed.", crate::kq_fx_refresh_pair(2, 1));
+        assert_eq!(Some(1.4), crate::kq_fx_get_rate(2, 1, date));
+        // other pairs are not re-queried
+        assert_eq!(None, crate::kq_fx_get_rate(3590000203070, 1, date));
+
+        crate::kq_fx_refresh_pair(3590000203070, 1);
+        assert_eq!(Some(0.68), crate::kq_fx_get_rate(3590000203070, 1, date));
+        assert_eq!(Some(1.4), crate::kq_fx_get_rate(2, 1, date));
+
+        crate::kq_fx_invalidate_cache();
     }
 
-    #[pg_test]
-    fn test_get_rate_by_id() {
+    /// Runs outside of a test transaction, so the statements commit and the commit callback of the
+    /// trigger runs.
+    #[cfg(test)]
+    #[test]
+    fn test_pair_triggers() {
+        const GET_RATE: &str = "SELECT kq_fx_get_rate(2, 1, '2024-04-15')";
+        const COUNT_TRIGGERS: &str = "SELECT count(*) FROM pg_trigger WHERE tgname IN ('kq_fx_refresh_pair', 'kq_fx_invalidate_cache')";
+        const UPDATE_RATE: &str = "UPDATE plan_pair_triggers.fx_rate SET rate = 1.4 WHERE currency_id = 2 AND to_currency_id = 1 AND \"date\" = '2024-03-01'";
+        pgrx_tests::run_test(
+            "test_always_ok",
+            None,
+            crate::pg_test::postgresql_conf_options(),
+        )
+        .unwrap();
+        let (mut client, _) = pgrx_tests::client().unwrap();
+
+        client
+            .batch_execute("SELECT kq_fx_install_pair_triggers('plan_pair_triggers.fx_rate', 'currency_id', 'to_currency_id')")
+            .unwrap();
         assert_eq!(
-            Some(1.2092987606763552f64),
-            crate::kq_fx_get_rate(2, 1, PgDate::new(2019, 12, 1).unwrap())
+            2,
+            client
+                .query_one(COUNT_TRIGGERS, &[])
+                .unwrap()
+                .get::<_, i64>(0)
         );
+        client
+            .batch_execute("SET kq.currency.rate_set = 'plan_pair_triggers'")
+            .unwrap();
         assert_eq!(
-            Some(1.6285458614035657f64),
-            crate::kq_fx_get_rate(
-                3590000203070,
-                3590000231158,
-                PgDate::new(2030, 1, 10).unwrap()
-            )
+            Some(1.3539),
+            client
+                .query_one(GET_RATE, &[])
+                .unwrap()
+                .get::<_, Option<f64>>(0)
         );
-    }
 
-    #[pg_test]
-    fn test_get_rate_by_xuid() {
+        // the pairs are only marked stale at commit
+        client.batch_execute("BEGIN").unwrap();
+        client.batch_execute(UPDATE_RATE).unwrap();
         assert_eq!(
-            Some(0.7335380076416401f64),
-            // 1 -> 2
-            crate::kq_fx_get_rate_xuid(
-                "usd".to_string(),
-                "cad".to_string(),
-                PgDate::new(2014, 2, 1).unwrap()
-            )
+            Some(1.3539),
+            client
+                .query_one(GET_RATE, &[])
+                .unwrap()
+                .get::<_, Option<f64>>(0)
         );
+        client.batch_execute("ROLLBACK").unwrap();
         assert_eq!(
-            Some(1.6285458614035657f64),
-            // 3590000203070 -> 3590000231158
-            crate::kq_fx_get_rate_xuid(
-                "aud".to_string(),
-                "nzd".to_string(),
-                PgDate::new(2030, 1, 10).unwrap()
-            )
+            Some(1.3539),
+            client
+                .query_one(GET_RATE, &[])
+                .unwrap()
+                .get::<_, Option<f64>>(0)
         );
-    }
 
-    #[pg_test]
-    fn test_try_get_less_than_min_date() {
+        client.batch_execute(UPDATE_RATE).unwrap();
+        assert_eq!(
+            Some(1.4),
+            client
+                .query_one(GET_RATE, &[])
+                .unwrap()
+                .get::<_, Option<f64>>(0)
+        );
+
+        client
+            .batch_execute("SELECT kq_fx_uninstall_triggers(ARRAY['plan_pair_triggers.fx_rate'])")
+            .unwrap();
         assert_eq!(
+            0,
+            client
+                .query_one(COUNT_TRIGGERS, &[])
+                .unwrap()
+                .get::<_, i64>(0)
+        );
+        client
+            .batch_execute("SELECT kq_fx_invalidate_cache()")
+            .unwrap();
+    }
+
+    /// Runs outside of a test transaction, so the lookups after the failed load are not aborted.
+    #[cfg(test)]
+    #[test]
+    fn test_failed_load() {
+        const GET_RATE: &str = "SELECT kq_fx_get_rate(2, 1, '2024-01-15')";
+        pgrx_tests::run_test(
+            "test_always_ok",
             None,
-            crate::kq_fx_get_rate(2, 1, PgDate::new(1999, 1, 1).unwrap())
+            crate::pg_test::postgresql_conf_options(),
+        )
+        .unwrap();
+        let (mut client, _) = pgrx_tests::client().unwrap();
+
+        client
+            .batch_execute(
+                "SET kq.currency.rate_set = 'plan_failed_load'; SET kq.currency.q3_get_currency_entries = 'SELECT 1 / 0'",
+            )
+            .unwrap();
+        let error = client.query_one(GET_RATE, &[]).unwrap_err();
+        assert_eq!(
+            Some("division by zero"),
+            error.as_db_error().map(|error| error.message())
+        );
+        // the failed load left no flags behind, the next lookup loads again
+        let error = client.query_one(GET_RATE, &[]).unwrap_err();
+        assert_eq!(
+            Some("division by zero"),
+            error.as_db_error().map(|error| error.message())
+        );
+
+        client
+            .batch_execute("RESET kq.currency.q3_get_currency_entries")
+            .unwrap();
+        assert_eq!(
+            Some(1.3425),
+            client
+                .query_one(GET_RATE, &[])
+                .unwrap()
+                .get::<_, Option<f64>>(0)
         );
+        client
+            .batch_execute("SELECT kq_fx_invalidate_cache()")
+            .unwrap();
     }
 
     #[pg_test]
-    fn test_try_get_greater_than_max_date() {
+    fn test_overflow_truncate() {
+        Spi::run("SET kq.currency.rate_set = 'plan_overflow_truncate'").unwrap();
+        Spi::run("SET kq.currency.overflow = 'truncate'").unwrap();
+        Spi::run("SET kq.currency.max_entries = 1").unwrap();
+        crate::kq_fx_invalidate_cache();
+        // Only the most recent entry of each pair is kept, 2024-03-01
+        assert_eq!(
+            None,
+            crate::kq_fx_get_rate(2, 1, PgDate::new(2024, 2, 15).unwrap())
+        );
         assert_eq!(
             Some(1.3539f64),
+            crate::kq_fx_get_rate(2, 1, PgDate::new(2024, 3, 15).unwrap())
+        );
+
+        Spi::run("SET kq.currency.max_entries = 0").unwrap();
+        Spi::run("SET kq.currency.max_pairs = 1").unwrap();
+        crate::kq_fx_invalidate_cache();
+        assert_eq!(
+            None,
+            crate::kq_fx_get_rate(3, 1, PgDate::new(2024, 1, 15).unwrap())
+        );
+
+        // The rates of the dropped currencies are dropped as well
+        Spi::run("SET kq.currency.max_pairs = 0").unwrap();
+        Spi::run("SET kq.currency.max_currencies = 4").unwrap();
+        crate::kq_fx_invalidate_cache();
+        assert_eq!(
+            None,
             crate::kq_fx_get_rate(
-                2,
-                1,
-                PgDate::new(2100, 1, 1).unwrap() // Max Date: 2024-03-01
+                3590000203070,
+                3590000231158,
+                PgDate::new(2030, 1, 10).unwrap()
             )
         );
+        assert_eq!(
+            Some(0.5366f64),
+            crate::kq_fx_get_rate(3, 1, PgDate::new(2024, 1, 15).unwrap())
+        );
+
+        crate::kq_fx_invalidate_cache();
+    }
+
+    #[pg_test(error = "cache load exceeds kq.currency.max_pairs")]
+    fn test_overflow_error() {
+        Spi::run("SET kq.currency.rate_set = 'plan_overflow_error'").unwrap();
+        Spi::run("SET kq.currency.max_pairs = 1").unwrap();
+        crate::kq_fx_populate_cache();
+    }
+
+    #[pg_test(error = "cache load exceeds kq.currency.max_entries")]
+    fn test_overflow_entries_error() {
+        Spi::run("SET kq.currency.rate_set = 'plan_overflow_entries'").unwrap();
+        Spi::run("SET kq.currency.max_entries = 1").unwrap();
+        crate::kq_fx_populate_cache();
+    }
+
+    #[pg_test(error = "No rate available for (2,1) at 2100-01-01")]
+    fn test_out_of_range_error() {
+        Spi::run("SET kq.currency.out_of_range = 'error'").unwrap();
+        crate::kq_fx_get_rate(2, 1, PgDate::new(2100, 1, 1).unwrap());
     }
 
     fn create_date(year: i32, month: u8, day: u8) -> PgDate {
@@ -0,0 +1,442 @@
+//! Read-only maps stored in a PostgreSQL dynamic shared memory area (DSA), along with the shared
+//! memory primitives the cache is built on.
+//!
+//! The maps are built in backend memory, then frozen into a single DSA allocation of the exact
+//! size they need, so the cache grows with the source tables without a restart. Each backend maps
+//! the area at its own address, so the frozen arrays are referenced by offsets from the start of
+//! the allocation and resolved into slices when read.
+
+use pgrx::pg_sys;
+use pgrx::shmem::PgSharedMemoryInitialization;
+use pgrx::PgMemoryContexts;
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::time::Duration;
+
+/// Alignment of every array inside a frozen allocation.
+const FROZEN_ALIGN: usize = 16;
+
+const fn align_up(size: usize) -> usize {
+    (size + FROZEN_ALIGN - 1) & !(FROZEN_ALIGN - 1)
+}
+
+/// A DSA created in the main shared memory at server start and attached by each backend on first
+/// use. The area only holds its control data until something is allocated, DSM segments are added
+/// as needed.
+pub struct SharedArea {
+    name: &'static CStr,
+    /// Main shared memory block: the LWLock tranche id of the area followed by the area itself.
+    control: AtomicPtr<u8>,
+    /// This backend's attachment.
+    area: AtomicPtr<pg_sys::dsa_area>,
+}
+
+/// A block in a `SharedArea`. `start` is aligned to `FROZEN_ALIGN` inside `allocation`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SharedAllocation {
+    allocation: pg_sys::dsa_pointer,
+    start: pg_sys::dsa_pointer,
+}
+
+impl SharedArea {
+    pub const fn new(name: &'static CStr) -> Self {
+        SharedArea {
+            name,
+            control: AtomicPtr::new(std::ptr::null_mut()),
+            area: AtomicPtr::new(std::ptr::null_mut()),
+        }
+    }
+
+    fn shmem_size(&self) -> usize {
+        FROZEN_ALIGN + unsafe { pg_sys::dsa_minimum_size() }
+    }
+
+    fn area(&self) -> *mut pg_sys::dsa_area {
+        let area = self.area.load(Ordering::Relaxed);
+        if !area.is_null() {
+            return area;
+        }
+        let control = self.control.load(Ordering::Relaxed);
+        if control.is_null() {
+            pgrx::error!(
+                "{} is not initialized, kq_fx must be loaded with shared_preload_libraries",
+                self.name.to_string_lossy()
+            );
+        }
+        unsafe {
+            pg_sys::LWLockRegisterTranche(*(control as *const i32), self.name.as_ptr());
+            // The attachment must outlive the current query.
+            let area = PgMemoryContexts::TopMemoryContext.switch_to(|_| {
+                pg_sys::dsa_attach_in_place(
+                    control.add(FROZEN_ALIGN) as *mut std::ffi::c_void,
+                    std::ptr::null_mut(),
+                )
+            });
+            pg_sys::dsa_pin_mapping(area);
+            self.area.store(area, Ordering::Relaxed);
+            area
+        }
+    }
+
+    /// Allocates `size` zeroed bytes, raising an error when out of memory.
+    pub fn allocate(&self, size: usize) -> SharedAllocation {
+        let area = self.area();
+        unsafe {
+            let allocation = pg_sys::dsa_allocate_extended(
+                area,
+                size + FROZEN_ALIGN,
+                (pg_sys::DSA_ALLOC_HUGE | pg_sys::DSA_ALLOC_ZERO) as i32,
+            );
+            // Segments are mapped at page boundaries, so the padding is the same in all backends.
+            let address = pg_sys::dsa_get_address(area, allocation) as *mut u8;
+            let padding = address.align_offset(FROZEN_ALIGN);
+            SharedAllocation {
+                allocation,
+                start: allocation + padding as pg_sys::dsa_pointer,
+            }
+        }
+    }
+
+    pub fn free(&self, allocation: SharedAllocation) {
+        unsafe { pg_sys::dsa_free(self.area(), allocation.allocation) }
+    }
+
+    /// Start of the allocation in this backend.
+    pub fn address(&self, allocation: SharedAllocation) -> *mut u8 {
+        unsafe { pg_sys::dsa_get_address(self.area(), allocation.start) as *mut u8 }
+    }
+}
+
+impl PgSharedMemoryInitialization for SharedArea {
+    fn pg_init(&'static self) {
+        unsafe {
+            pg_sys::RequestAddinShmemSpace(self.shmem_size());
+        }
+    }
+
+    unsafe fn shmem_init(&'static self) {
+        let size = self.shmem_size();
+        let mut found = false;
+        unsafe {
+            // AddinShmemInitLock
+            let addin_shmem_init_lock: *mut pg_sys::LWLock =
+                &mut (*pg_sys::MainLWLockArray.add(21)).lock;
+            pg_sys::LWLockAcquire(addin_shmem_init_lock, pg_sys::LWLockMode::LW_EXCLUSIVE);
+
+            let control = pg_sys::ShmemInitStruct(self.name.as_ptr(), size, &mut found) as *mut u8;
+            if !found {
+                let tranche_id = pg_sys::LWLockNewTrancheId();
+                *(control as *mut i32) = tranche_id;
+                let place = control.add(FROZEN_ALIGN) as *mut std::ffi::c_void;
+                let place_size = size - FROZEN_ALIGN;
+                #[cfg(not(feature = "pg17"))]
+                let area = pg_sys::dsa_create_in_place(
+                    place,
+                    place_size,
+                    tranche_id,
+                    std::ptr::null_mut(),
+                );
+                #[cfg(feature = "pg17")]
+                let area = pg_sys::dsa_create_in_place_ext(
+                    place,
+                    place_size,
+                    tranche_id,
+                    std::ptr::null_mut(),
+                    // DSA_DEFAULT_INIT_SEGMENT_SIZE, DSA_MAX_SEGMENT_SIZE
+                    1024 * 1024,
+                    1 << pg_sys::DSA_OFFSET_WIDTH,
+                );
+                // Keep the area when no backend is attached, the postmaster itself never uses it.
+                pg_sys::dsa_pin(area);
+                pg_sys::dsa_detach(area);
+            }
+            self.control.store(control, Ordering::Relaxed);
+
+            pg_sys::LWLockRelease(addin_shmem_init_lock);
+        }
+    }
+}
+
+/// A condition variable in the main shared memory, created at server start.
+pub struct SharedConditionVariable {
+    name: &'static CStr,
+    cv: AtomicPtr<pg_sys::ConditionVariable>,
+}
+
+impl SharedConditionVariable {
+    pub const fn new(name: &'static CStr) -> Self {
+        SharedConditionVariable {
+            name,
+            cv: AtomicPtr::new(std::ptr::null_mut()),
+        }
+    }
+
+    fn cv(&self) -> *mut pg_sys::ConditionVariable {
+        let cv = self.cv.load(Ordering::Relaxed);
+        if cv.is_null() {
+            pgrx::error!(
+                "{} is not initialized, kq_fx must be loaded with shared_preload_libraries",
+                self.name.to_string_lossy()
+            );
+        }
+        cv
+    }
+
+    /// Sleeps until a broadcast or `timeout`, reporting `wait_event_info` meanwhile. Returns
+    /// immediately on the first call of a wait, so the caller checks its condition again. Query
+    /// cancellation and `statement_timeout` interrupt the sleep with an error.
+    pub fn sleep(&self, timeout: Duration, wait_event_info: u32) {
+        let timeout = timeout.as_millis().clamp(1, std::ffi::c_long::MAX as u128);
+        unsafe {
+            pg_sys::ConditionVariableTimedSleep(
+                self.cv(),
+                timeout as std::ffi::c_long,
+                wait_event_info,
+            );
+        }
+    }
+
+    /// Ends a wait started by `sleep`, aborted transactions end it automatically.
+    pub fn cancel_sleep(&self) {
+        unsafe {
+            pg_sys::ConditionVariableCancelSleep();
+        }
+    }
+
+    pub fn broadcast(&self) {
+        unsafe { pg_sys::ConditionVariableBroadcast(self.cv()) }
+    }
+}
+
+impl PgSharedMemoryInitialization for SharedConditionVariable {
+    fn pg_init(&'static self) {
+        unsafe {
+            pg_sys::RequestAddinShmemSpace(std::mem::size_of::<pg_sys::ConditionVariable>());
+        }
+    }
+
+    unsafe fn shmem_init(&'static self) {
+        let mut found = false;
+        unsafe {
+            // AddinShmemInitLock
+            let addin_shmem_init_lock: *mut pg_sys::LWLock =
+                &mut (*pg_sys::MainLWLockArray.add(21)).lock;
+            pg_sys::LWLockAcquire(addin_shmem_init_lock, pg_sys::LWLockMode::LW_EXCLUSIVE);
+
+            let cv = pg_sys::ShmemInitStruct(
+                self.name.as_ptr(),
+                std::mem::size_of::<pg_sys::ConditionVariable>(),
+                &mut found,
+            ) as *mut pg_sys::ConditionVariable;
+            if !found {
+                pg_sys::ConditionVariableInit(cv);
+            }
+            self.cv.store(cv, Ordering::Relaxed);
+
+            pg_sys::LWLockRelease(addin_shmem_init_lock);
+        }
+    }
+}
+
+/// Offset and length of an array inside a frozen allocation.
+#[repr(C)]
+pub struct FrozenSlice<T> {
+    offset: usize,
+    len: usize,
+    _items: PhantomData<T>,
+}
+
+impl<T> Clone for FrozenSlice<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for FrozenSlice<T> {}
+
+impl<T> FrozenSlice<T> {
+    /// # Safety
+    ///
+    /// `base` must be the start of an allocation of the layout that reserved this slice, written
+    /// with `write`, and valid for `'a`.
+    unsafe fn get<'a>(&self, base: *const u8) -> &'a [T] {
+        std::slice::from_raw_parts(base.add(self.offset) as *const T, self.len)
+    }
+
+    /// # Safety
+    ///
+    /// `base` must be the start of a zeroed allocation of the layout that reserved this slice.
+    unsafe fn write(&self, base: *mut u8, items: impl Iterator<Item = T>) {
+        let start = base.add(self.offset) as *mut T;
+        let mut len = 0;
+        for item in items.take(self.len) {
+            std::ptr::write(start.add(len), item);
+            len += 1;
+        }
+        assert_eq!(
+            len, self.len,
+            "frozen slice written with fewer items than reserved"
+        );
+    }
+}
+
+/// Computes the size of a frozen allocation: a header `H` followed by the arrays it references.
+pub struct FrozenLayout {
+    size: usize,
+}
+
+impl FrozenLayout {
+    pub fn new<H>() -> Self {
+        FrozenLayout {
+            size: std::mem::size_of::<H>(),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn reserve<T>(&mut self, len: usize) -> FrozenSlice<T> {
+        assert!(std::mem::align_of::<T>() <= FROZEN_ALIGN);
+        let offset = align_up(self.size);
+        self.size = offset + len * std::mem::size_of::<T>();
+        FrozenSlice {
+            offset,
+            len,
+            _items: PhantomData,
+        }
+    }
+}
+
+/// Frozen `BTreeMap`, an array of (key, value) sorted by key.
+#[repr(C)]
+pub struct FrozenMap<K, V> {
+    entries: FrozenSlice<(K, V)>,
+}
+
+impl<K: Ord + Clone, V: Clone> FrozenMap<K, V> {
+    pub fn reserve(layout: &mut FrozenLayout, map: &BTreeMap<K, V>) -> Self {
+        FrozenMap {
+            entries: layout.reserve(map.len()),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// See `FrozenSlice::write`, `map` must be the one given to `reserve`.
+    pub unsafe fn write(&self, base: *mut u8, map: &BTreeMap<K, V>) {
+        self.entries.write(
+            base,
+            map.iter().map(|(key, value)| (key.clone(), value.clone())),
+        );
+    }
+
+    /// # Safety
+    ///
+    /// See `FrozenSlice::get`.
+    pub unsafe fn get<'a>(&self, base: *const u8) -> FrozenMapRef<'a, K, V> {
+        FrozenMapRef {
+            entries: self.entries.get(base),
+        }
+    }
+}
+
+pub struct FrozenMapRef<'a, K, V> {
+    entries: &'a [(K, V)],
+}
+
+impl<K, V> Default for FrozenMapRef<'_, K, V> {
+    fn default() -> Self {
+        FrozenMapRef { entries: &[] }
+    }
+}
+
+impl<'a, K: Ord, V> FrozenMapRef<'a, K, V> {
+    pub fn get(&self, key: &K) -> Option<&'a V> {
+        let index = self
+            .entries
+            .binary_search_by(|(entry_key, _)| entry_key.cmp(key))
+            .ok()?;
+        Some(&self.entries[index].1)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'a K, &'a V)> {
+        self.entries.iter().map(|(key, value)| (key, value))
+    }
+}
+
+/// Frozen `BTreeMap` of vectors. The vectors are stored back to back in a single items array, each
+/// key holds the range of its items.
+#[repr(C)]
+pub struct FrozenVecMap<K, T> {
+    keys: FrozenSlice<(K, usize, usize)>,
+    items: FrozenSlice<T>,
+}
+
+impl<K: Ord + Clone, T: Clone> FrozenVecMap<K, T> {
+    pub fn reserve(layout: &mut FrozenLayout, map: &BTreeMap<K, Vec<T>>) -> Self {
+        FrozenVecMap {
+            keys: layout.reserve(map.len()),
+            items: layout.reserve(map.values().map(Vec::len).sum()),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// See `FrozenSlice::write`, `map` must be the one given to `reserve`.
+    pub unsafe fn write(&self, base: *mut u8, map: &BTreeMap<K, Vec<T>>) {
+        let mut start = 0;
+        self.keys.write(
+            base,
+            map.iter().map(|(key, items)| {
+                start += items.len();
+                (key.clone(), start - items.len(), start)
+            }),
+        );
+        self.items.write(base, map.values().flatten().cloned());
+    }
+
+    /// # Safety
+    ///
+    /// See `FrozenSlice::get`.
+    pub unsafe fn get<'a>(&self, base: *const u8) -> FrozenVecMapRef<'a, K, T> {
+        FrozenVecMapRef {
+            keys: self.keys.get(base),
+            items: self.items.get(base),
+        }
+    }
+}
+
+pub struct FrozenVecMapRef<'a, K, T> {
+    keys: &'a [(K, usize, usize)],
+    items: &'a [T],
+}
+
+impl<K, T> Default for FrozenVecMapRef<'_, K, T> {
+    fn default() -> Self {
+        FrozenVecMapRef {
+            keys: &[],
+            items: &[],
+        }
+    }
+}
+
+impl<'a, K: Ord, T> FrozenVecMapRef<'a, K, T> {
+    pub fn get(&self, key: &K) -> Option<&'a [T]> {
+        let index = self
+            .keys
+            .binary_search_by(|(entry_key, _, _)| entry_key.cmp(key))
+            .ok()?;
+        let (_, start, end) = self.keys[index];
+        Some(&self.items[start..end])
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'a K, &'a [T])> {
+        let items = self.items;
+        self.keys
+            .iter()
+            .map(move |(key, start, end)| (key, &items[*start..*end]))
+    }
+}